@@ -1,31 +1,102 @@
-use super::weather::{DailyWeather, WeatherInfo};
+use super::chart::ChartPage;
+use super::fetcher::WeatherFetcher;
+use super::moon::{draw_moon, moon_phase};
+use super::page::{Page, RenderContext};
+use super::qr::draw_qr;
+use super::qweather::{forget_city, QWeatherProvider};
+use super::strings::{label, weekday, Label, Language};
+use super::text::{draw_wrapped_text, text_width, wrap_text, NoteFont};
+use super::units::Units;
+use super::weather::{CurrentWeather, DailyWeather, HourlyWeather, WeatherAlert, WeatherInfo};
 use super::weather_icons::extract_icon;
+use super::wind::{draw_wind_arrow, wind_bearing};
+use crate::climate::{comfort_level, ComfortLevel};
 use crate::config::Config;
 use crate::display::{Color, Display};
-use crate::error::Result;
-use crate::network::http::HttpServer;
+use crate::error::{Result, WmError};
+use crate::log;
+use crate::network::http::{HttpServer, StatusReport, IMAGE_HEIGHT, IMAGE_WIDTH};
+use crate::network::mqtt::MqttPublisher;
 use crate::network::wifi::WifiDevice;
-use crate::peripheral::{dht20::DHT20, ssd1683::SSD1683};
+use crate::peripheral::{
+    battery::Battery,
+    button::Button,
+    device::Device,
+    sensor::{IndoorSensor, Reading, Smoothed},
+    watchdog::Watchdog,
+};
+use crate::settings::Settings;
 
 use embedded_graphics::prelude::*;
 use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, StyledDrawable};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use u8g2_fonts::{fonts, types::*, FontRenderer};
 
+use std::collections::VecDeque;
 use std::thread::sleep;
 use std::time::Duration;
-use time::{OffsetDateTime, Weekday};
-use time_macros::offset;
+use time::OffsetDateTime;
 
-fn show_status(display: &mut Display, wifi: &WifiDevice, now: &OffsetDateTime) -> Result<()> {
-    let content = format!(
-        "{} | {:02}:{:02} | V2.2",
+const SENSOR_SMOOTHING_WINDOW: usize = 3;
+// Four hours of five minute samples
+const SPARKLINE_LEN: usize = 48;
+// A day of five minute samples for the chart page
+const HISTORY_LEN: usize = 12 * 24;
+// Full refreshes before switching to the next page
+const PAGE_REFRESHES: u32 = 3;
+// Height of the note area between the banner and the sparkline
+const NOTE_HEIGHT: i32 = 64;
+const FORECAST_CHART_DAYS: usize = 7;
+// The AQI bar spans the banner right of the icon and tops out at AQI_MAX
+const AQI_BAR_WIDTH: u32 = 180;
+const AQI_MAX: i32 = 500;
+const AQI_UNHEALTHY: i32 = 100;
+// How often the weather is polled for changes between scheduled refreshes
+const WEATHER_CHECK_MINUTES: u8 = 15;
+// Refreshes resume only this far above the low battery threshold, as the
+// cell voltage bounces back a little once the load is gone
+const LOW_BATTERY_HYSTERESIS_MV: u32 = 100;
+
+fn show_status(
+    display: &mut Display,
+    wifi: &WifiDevice,
+    battery: Option<u8>,
+    weather: &WeatherInfo,
+    now: Option<&OffsetDateTime>,
+    lang: Language,
+) -> Result<()> {
+    let rssi = match wifi.rssi() {
+        Ok(rssi) => format!("{}dBm", rssi),
+        Err(_) => String::from("N/A"),
+    };
+    let clock = match now {
+        Some(now) => format!("{:02}:{:02}", now.hour(), now.minute()),
+        None => String::from("--:--"),
+    };
+    let mut content = format!(
+        "{} | {} | {} | {} | V2.2",
+        wifi.ssid(),
         wifi.ip_addr().unwrap_or(String::from("N/A")),
-        now.hour(),
-        now.minute()
+        rssi,
+        clock
     );
+    if let Some(battery) = battery {
+        content = format!("{}% | {}", battery, content);
+    }
 
-    let position = Point::new(display.get_width() as i32, display.get_height() as i32);
+    let size = display.size();
+    let position = Point::new(size.width as i32, size.height as i32);
     let font = FontRenderer::new::<fonts::u8g2_font_6x10_mf>().with_ignore_unknown_chars(true);
+    // Blank whatever the page drew under the status text so it stays legible
+    let width = (text_width(&font, &content)? as u32).min(size.width);
+    let height = font.get_default_line_height().min(size.height);
+    display.clear_region(
+        (size.width - width) as usize,
+        (size.height - height) as usize,
+        width as usize,
+        height as usize,
+        Color::White,
+    )?;
     font.render_aligned(
         &content as &str,
         position,
@@ -35,10 +106,125 @@ fn show_status(display: &mut Display, wifi: &WifiDevice, now: &OffsetDateTime) -
         display,
     )?;
 
+    // Without a synchronized clock a red stopwatch takes the place of the
+    // update time, as the time shown is meaningless
+    if now.is_none() {
+        let position = position - Point::new(text_width(&font, &content)? + 6, 0);
+        let font = FontRenderer::new::<fonts::u8g2_font_unifont_t_symbols>()
+            .with_ignore_unknown_chars(true);
+        font.render_aligned(
+            "\u{23F1}",
+            position,
+            VerticalPosition::Bottom,
+            HorizontalAlignment::Right,
+            FontColor::Transparent(Color::Red),
+            display,
+        )?;
+    }
+
+    // Time of the last weather update left of the status, red once stale
+    if let Some(updated) = now.and_then(|now| weather.last_update(*now)) {
+        let stamp = format!(
+            "{} {:02}:{:02}",
+            label(lang, Label::Updated),
+            updated.hour(),
+            updated.minute()
+        );
+        let color = if weather.is_stale() {
+            Color::Red
+        } else {
+            Color::Black
+        };
+        let position = position - Point::new(text_width(&font, &content)? + 6, 0);
+        let font =
+            FontRenderer::new::<fonts::u8g2_font_wqy12_t_gb2312a>().with_ignore_unknown_chars(true);
+        font.render_aligned(
+            &stamp as &str,
+            position,
+            VerticalPosition::Bottom,
+            HorizontalAlignment::Right,
+            FontColor::Transparent(color),
+            display,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Overlays a red strip across the top of the page with the title of the
+/// first alert, noting how many more there are. Draws nothing without alerts.
+fn draw_alert_banner(display: &mut Display, alerts: &[WeatherAlert]) -> Result<()> {
+    let first = match alerts.first() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    // Some issuers leave the title empty and only fill in the text
+    let mut content = if first.title.is_empty() {
+        first.text.clone()
+    } else {
+        first.title.clone()
+    };
+    if alerts.len() > 1 {
+        content = format!("{} (+{})", content, alerts.len() - 1);
+    }
+    let font =
+        FontRenderer::new::<fonts::u8g2_font_wqy16_t_gb2312>().with_ignore_unknown_chars(true);
+    let size = display.size();
+    let height = font.get_default_line_height() + 4;
+    Rectangle::new(Point::zero(), Size::new(size.width, height))
+        .draw_styled(&PrimitiveStyle::with_fill(Color::Red), display)?;
+    font.render_aligned(
+        &content as &str,
+        Point::new(size.width as i32 / 2, height as i32 / 2),
+        VerticalPosition::Center,
+        HorizontalAlignment::Center,
+        FontColor::Transparent(Color::White),
+        display,
+    )?;
     Ok(())
 }
 
-fn draw_today(display: &mut Display, base_point: Point, now: &OffsetDateTime) -> Result<()> {
+/// Replaces the whole screen with an empty battery and a request to charge.
+/// It is the last frame before refreshes stop, so it stays on the panel once
+/// the battery is flat.
+fn draw_low_battery(display: &mut Display, voltage: f32, lang: Language) -> Result<()> {
+    let size = display.size();
+    let center = Point::new(size.width as i32 / 2, size.height as i32 / 2);
+    let body = Size::new(120, 60);
+    let origin = center - Point::new(body.width as i32 / 2, body.height as i32);
+    Rectangle::new(origin, body)
+        .draw_styled(&PrimitiveStyle::with_stroke(Color::Black, 4), display)?;
+    // Terminal nub on the right
+    Rectangle::new(
+        origin + Point::new(body.width as i32, body.height as i32 / 2 - 12),
+        Size::new(8, 24),
+    )
+    .draw_styled(&PrimitiveStyle::with_fill(Color::Black), display)?;
+    // A sliver of red charge left
+    Rectangle::new(origin + Point::new(8, 8), Size::new(16, body.height - 16))
+        .draw_styled(&PrimitiveStyle::with_fill(Color::Red), display)?;
+
+    let content = format!("{} ({:.2}V)", label(lang, Label::LowBattery), voltage);
+    let font =
+        FontRenderer::new::<fonts::u8g2_font_wqy16_t_gb2312>().with_ignore_unknown_chars(true);
+    font.render_aligned(
+        &content as &str,
+        center + Point::new(0, 24),
+        VerticalPosition::Center,
+        HorizontalAlignment::Center,
+        FontColor::Transparent(Color::Red),
+        display,
+    )?;
+    Ok(())
+}
+
+/// Draws the date block, or placeholders while the clock is not synchronized.
+fn draw_today(
+    display: &mut Display,
+    base_point: Point,
+    now: Option<&OffsetDateTime>,
+    lang: Language,
+) -> Result<()> {
     Rectangle::new(
         base_point,
         Size {
@@ -53,15 +239,30 @@ fn draw_today(display: &mut Display, base_point: Point, now: &OffsetDateTime) ->
     // Rectangle::new(base_point, Size { width, height: 4 })
     //     .draw_styled(&PrimitiveStyle::with_fill(Color::White), display)?;
 
+    // Draw moon phase in the top right corner
+    if let Some(now) = now {
+        let position = base_point + Point::new(128 - 14, 14);
+        draw_moon(
+            display,
+            position.x,
+            position.y,
+            9,
+            moon_phase(*now),
+            Color::White,
+        )?;
+    }
+
     // Draw Day
-    let content = format!("{}", now.day());
+    let content = match now {
+        Some(now) => format!("{}", now.day()),
+        None => String::from("--"),
+    };
     let position = base_point
         + Point {
             x: 128 / 2,
             y: 128 / 2,
         };
-    let font =
-        FontRenderer::new::<fonts::u8g2_font_logisoso46_tn>().with_ignore_unknown_chars(true);
+    let font = fit_font(&content, Size::new(128 - 16, 128 / 2))?;
     font.render_aligned(
         &content as &str,
         position,
@@ -72,12 +273,15 @@ fn draw_today(display: &mut Display, base_point: Point, now: &OffsetDateTime) ->
     )?;
 
     // Draw YY/MM and Weekday
-    let content = format!(
-        "{}/{} {}",
-        now.year(),
-        now.month() as i32,
-        weekday_to_string(now.weekday())
-    );
+    let content = match now {
+        Some(now) => format!(
+            "{}/{} {}",
+            now.year(),
+            now.month() as i32,
+            weekday(lang, now.weekday())
+        ),
+        None => String::from("----/--"),
+    };
     let font =
         FontRenderer::new::<fonts::u8g2_font_wqy16_t_gb2312>().with_ignore_unknown_chars(true);
     let position = base_point
@@ -98,9 +302,16 @@ fn draw_today(display: &mut Display, base_point: Point, now: &OffsetDateTime) ->
     Ok(())
 }
 
-fn draw_attribute(display: &mut Display, base_point: Point, key: &str, value: &str) -> Result<()> {
+fn draw_attribute(
+    display: &mut Display,
+    base_point: Point,
+    key: &str,
+    value: &str,
+    color: Color,
+) -> Result<Size> {
     let font =
         FontRenderer::new::<fonts::u8g2_font_wqy12_t_gb2312a>().with_ignore_unknown_chars(true);
+    let key_size = text_size(&font, key)?;
 
     let position = base_point + Point { x: 0, y: 0 };
     font.render_aligned(
@@ -108,31 +319,31 @@ fn draw_attribute(display: &mut Display, base_point: Point, key: &str, value: &s
         position,
         VerticalPosition::Top,
         HorizontalAlignment::Left,
-        FontColor::Transparent(Color::Red),
+        FontColor::Transparent(color),
         display,
     )?;
 
     let font =
         FontRenderer::new::<fonts::u8g2_font_logisoso16_tr>().with_ignore_unknown_chars(true);
+    let value_size = text_size(&font, value)?;
     let position = base_point + Point { x: 0, y: 17 };
     font.render_aligned(
         value,
         position,
         VerticalPosition::Top,
         HorizontalAlignment::Left,
-        FontColor::Transparent(Color::Red),
+        FontColor::Transparent(color),
         display,
     )?;
 
-    Ok(())
+    Ok(Size::new(
+        key_size.width.max(value_size.width),
+        17 + value_size.height,
+    ))
 }
 
-fn draw_top_banner(
-    display: &mut Display,
-    base_point: Point,
-    weather: &WeatherInfo,
-    sensor: (f32, f32),
-) -> Result<()> {
+fn draw_top_banner(display: &mut Display, base_point: Point, ctx: &RenderContext) -> Result<()> {
+    let (weather, sensor, units, lang) = (ctx.weather, ctx.sensor, ctx.units, ctx.lang);
     if let Some(bitmap) = extract_icon(weather.now.icon) {
         display.bitmap(
             base_point.x as usize,
@@ -144,21 +355,26 @@ fn draw_top_banner(
         )?;
     }
 
-    let content = if weather.now.aqi_primary == "NA" {
+    if weather.valid {
+        draw_precipitation(
+            display,
+            base_point + Point::new(0, 64 + 2),
+            weather.now.precipitation,
+            weather.now.icon,
+        )?;
+    }
+
+    let air = if weather.now.aqi_primary == "NA" {
         format!(
-            "{} {} {} 级\n空气质量 {} ({})",
-            weather.now.text,
-            weather.now.wind_dir,
-            weather.now.wind_scale,
+            "{} {} ({})",
+            label(lang, Label::AirQuality),
             weather.now.aqi_category,
             weather.now.aqi
         )
     } else {
         format!(
-            "{} {} {} 级\n空气质量 {} ({}) {}",
-            weather.now.text,
-            weather.now.wind_dir,
-            weather.now.wind_scale,
+            "{} {} ({}) {}",
+            label(lang, Label::AirQuality),
             weather.now.aqi_category,
             weather.now.aqi,
             weather.now.aqi_primary
@@ -168,50 +384,225 @@ fn draw_top_banner(
     if weather.valid {
         let font =
             FontRenderer::new::<fonts::u8g2_font_wqy16_t_gb2312>().with_ignore_unknown_chars(true);
+        let origin = base_point + Point::new(64 + 8, 4);
+        let wind = format!(
+            "{} {}",
+            weather.now.wind_scale,
+            label(lang, Label::WindLevel)
+        );
+        // The arrow takes the place of the direction name when it is known
+        let content = match wind_bearing(weather.now.wind_360, &weather.now.wind_dir) {
+            Some(bearing) => {
+                let prefix = format!("{} ", weather.now.text);
+                let x = origin.x + text_width(&font, &prefix)?;
+                draw_wind_arrow(display, x + 7, origin.y + 8, 6, bearing, Color::Black)?;
+                font.render_aligned(
+                    &wind as &str,
+                    Point::new(x + 16, origin.y),
+                    VerticalPosition::Top,
+                    HorizontalAlignment::Left,
+                    FontColor::Transparent(Color::Black),
+                    display,
+                )?;
+                prefix
+            }
+            None => format!("{} {} {}", weather.now.text, weather.now.wind_dir, wind),
+        };
         font.render_aligned(
             &content as &str,
-            base_point + Point::new(64 + 8, 4),
+            origin,
             VerticalPosition::Top,
             HorizontalAlignment::Left,
             FontColor::Transparent(Color::Black),
             display,
         )?;
+        font.render_aligned(
+            &air as &str,
+            origin + Point::new(0, font.get_default_line_height() as i32),
+            VerticalPosition::Top,
+            HorizontalAlignment::Left,
+            FontColor::Transparent(Color::Black),
+            display,
+        )?;
+        draw_aqi_bar(
+            display,
+            origin + Point::new(0, 2 * font.get_default_line_height() as i32 + 1),
+            weather.now.aqi,
+            &weather.now.aqi_category,
+        )?;
     }
 
+    // Outdated values are drawn in black instead of the highlight color
+    let outdoor_color = if weather.is_stale() {
+        Color::Black
+    } else {
+        Color::Red
+    };
+
     let position = base_point + Point::new(64 + 8, 24 + 20);
-    let content = format!("{}|{}", weather.now.temperature, weather.now.humidity);
+    let content = format!(
+        "{}|{}",
+        units.degrees(weather.now.temperature),
+        weather.now.humidity
+    );
+    // Long outdoor values push the indoor block to the right
+    let mut indoor_offset = 96;
     if weather.valid {
-        draw_attribute(display, position, "室外 °C|%", &content)?;
+        let size = draw_attribute(
+            display,
+            position,
+            &format!(
+                "{} {}|%",
+                label(lang, Label::Outdoor),
+                units.temperature_symbol()
+            ),
+            &content,
+            outdoor_color,
+        )?;
+        indoor_offset = indoor_offset.max(size.width as i32 + 8);
     }
 
-    let position = base_point + Point::new(64 + 8 + 96, 24 + 20);
-    let content = format!("{:.1}|{:.1}", sensor.0, sensor.1);
-    draw_attribute(display, position, "室内 °C|%", &content)?;
+    let position = base_point + Point::new(64 + 8 + indoor_offset, 24 + 20);
+    let mut content = match sensor {
+        Some((temperature, humidity)) => {
+            format!("{:.1}|{:.1}", units.temperature(temperature), humidity)
+        }
+        None => String::from("N/A"),
+    };
+    let mut key = format!(
+        "{} {}|%",
+        label(lang, Label::Indoor),
+        units.temperature_symbol()
+    );
+    // A sensor with a barometer adds the pressure as a third value
+    if let (Some(_), Some(pressure)) = (sensor, ctx.pressure) {
+        content = format!("{}|{}", content, units.pressure(pressure));
+        key = format!("{}|{}", key, units.pressure_symbol());
+    }
+    draw_attribute(display, position, &key, &content, Color::Red)?;
+
+    // How the room feels, next to the key so long values don't push it out
+    if let Some((temperature, humidity)) = sensor {
+        let comfort = match comfort_level(temperature, humidity) {
+            ComfortLevel::Comfortable => Label::Comfortable,
+            ComfortLevel::Dry => Label::Dry,
+            ComfortLevel::Humid => Label::Humid,
+        };
+        let font =
+            FontRenderer::new::<fonts::u8g2_font_wqy12_t_gb2312a>().with_ignore_unknown_chars(true);
+        font.render_aligned(
+            label(lang, comfort),
+            position + Point::new(text_width(&font, &key)? + 4, 0),
+            VerticalPosition::Top,
+            HorizontalAlignment::Left,
+            FontColor::Transparent(Color::Red),
+            display,
+        )?;
+    }
 
     if !weather.valid {
         return Ok(());
     }
 
-    let position = base_point + Point::new(0, 24 + 64);
-    let content = format!("{}", weather.now.aqi_pm10);
-    draw_attribute(display, position, "PM10 ug", &content)?;
-
-    let position = position + Point::new(36 + 16, 0);
-    let content = format!("{}", weather.now.aqi_pm2p5);
-    draw_attribute(display, position, "PM2.5 ug", &content)?;
+    // The gas readings replace the second half of the row when asked for and
+    // reported, PM10 and PM2.5 stay in front either way
+    let mut columns = vec![
+        ("PM10 ug".to_string(), format!("{}", weather.now.aqi_pm10)),
+        ("PM2.5 ug".to_string(), format!("{}", weather.now.aqi_pm2p5)),
+    ];
+    if ctx.air_details && weather.now.has_gases() {
+        columns.extend([
+            ("SO2".to_string(), format!("{}", weather.now.aqi_so2)),
+            ("NO2".to_string(), format!("{}", weather.now.aqi_no2)),
+            ("CO".to_string(), format!("{:.1}", weather.now.aqi_co)),
+            ("O3".to_string(), format!("{}", weather.now.aqi_o3)),
+        ]);
+    } else {
+        columns.extend([
+            (
+                label(lang, Label::Precipitation).to_string(),
+                format!("{:.1}", weather.now.precipitation),
+            ),
+            (
+                format!(
+                    "{} {}",
+                    label(lang, Label::FeelsLike),
+                    units.temperature_symbol()
+                ),
+                format!("{}", units.degrees(weather.now.feels_like)),
+            ),
+            (
+                // English has no word in front of the unit
+                format!(
+                    "{} {}",
+                    label(lang, Label::Pressure),
+                    units.pressure_symbol()
+                )
+                .trim_start()
+                .to_string(),
+                units.pressure(weather.now.pressure as f32),
+            ),
+            (
+                "UV|km".to_string(),
+                format!("{}|{}", weather.now.uv_index, weather.now.visibility),
+            ),
+        ]);
+    }
 
-    let position = position + Point::new(36 + 16, 0);
-    let content = format!("{:.1}", weather.now.precipitation);
-    draw_attribute(display, position, "降水 mm", &content)?;
+    // Six columns share the 256px wide banner, a wider value takes more room
+    let mut position = base_point + Point::new(0, 24 + 64);
+    for (key, value) in columns.iter() {
+        let size = draw_attribute(display, position, key, value, outdoor_color)?;
+        position += Point::new(43.max(size.width as i32 + 4), 0);
+    }
 
-    let position = position + Point::new(36 + 16, 0);
-    let content = format!("{:.1}", weather.now.feels_like);
-    draw_attribute(display, position, "体感 °C", &content)?;
+    Ok(())
+}
 
-    let position = position + Point::new(36 + 16, 0);
-    let content = format!("{}", weather.now.pressure);
-    draw_attribute(display, position, "气压 hPa", &content)?;
+/// Draws the AQI as a 4px high bar at `base_point`, filled in proportion on
+/// the 0-500 scale and in red once the air is unhealthy (above 100). Nothing
+/// is drawn when the provider reported no category.
+fn draw_aqi_bar(display: &mut Display, base_point: Point, aqi: i32, category: &str) -> Result<()> {
+    if category.is_empty() {
+        return Ok(());
+    }
+    let color = if aqi > AQI_UNHEALTHY {
+        Color::Red
+    } else {
+        Color::Black
+    };
+    Rectangle::new(base_point, Size::new(AQI_BAR_WIDTH, 4))
+        .draw_styled(&PrimitiveStyle::with_stroke(Color::Black, 1), display)?;
+    let width = aqi.clamp(0, AQI_MAX) as u32 * AQI_BAR_WIDTH / AQI_MAX as u32;
+    Rectangle::new(base_point, Size::new(width, 4))
+        .draw_styled(&PrimitiveStyle::with_fill(color), display)?;
+    Ok(())
+}
 
+/// Draws slanted strokes (or dots for snow icons) in the 64x20 strip at
+/// `top_left`, one per half millimeter up to eight. Nothing is drawn when
+/// it is dry.
+fn draw_precipitation(
+    display: &mut Display,
+    top_left: Point,
+    precipitation: f32,
+    icon: i32,
+) -> Result<()> {
+    if precipitation <= 0.0 {
+        return Ok(());
+    }
+    let count = ((precipitation * 2.0).ceil() as i32).clamp(1, 8);
+    // QWeather uses the 4xx icon codes for snow
+    let snow = (400..500).contains(&icon);
+    for idx in 0..count {
+        let x = top_left.x + 4 + idx * 8;
+        if snow {
+            display.filled_circle(x + 2, top_left.y + 5, 1, Color::Red)?;
+            display.filled_circle(x, top_left.y + 14, 1, Color::Red)?;
+        } else {
+            display.line(x + 4, top_left.y + 2, x, top_left.y + 17, Color::Red)?;
+        }
+    }
     Ok(())
 }
 
@@ -219,9 +610,12 @@ fn draw_forecast_item(
     display: &mut Display,
     base_point: Point,
     entry: &DailyWeather,
+    previous: Option<&DailyWeather>,
     is_today: bool,
+    ctx: &RenderContext,
 ) -> Result<()> {
-    let icon = build_32x32_icon(entry.icon);
+    let (units, lang) = (ctx.units, ctx.lang);
+    let icon = build_32x32_icon(entry.icon, ctx.icon_threshold);
 
     if !icon.is_empty() {
         display.bitmap(
@@ -234,22 +628,34 @@ fn draw_forecast_item(
         )?;
     }
 
+    // Chance of rain as a bar below the icon
+    if let Some(precip_prob) = entry.precip_prob {
+        let position = base_point + Point::new(0, 34);
+        Rectangle::new(position, Size::new(32, 4))
+            .draw_styled(&PrimitiveStyle::with_stroke(Color::Black, 1), display)?;
+        let width = (precip_prob.clamp(0, 100) * 32 / 100) as u32;
+        Rectangle::new(position, Size::new(width, 4))
+            .draw_styled(&PrimitiveStyle::with_fill(Color::Black), display)?;
+    }
+
+    let range = format!(
+        "{}~{}{}",
+        units.degrees(entry.temp_min),
+        units.degrees(entry.temp_max),
+        units.temperature_symbol()
+    );
     let content = if is_today {
         format!(
-            "{}\n{}~{}°C\n日出 {}\n日落 {}",
-            &entry.date[5..=9],
-            entry.temp_min,
-            entry.temp_max,
+            "{}\n{}\n{} {}\n{} {}",
+            entry.month_day(),
+            range,
+            label(lang, Label::Sunrise),
             entry.sunrise,
+            label(lang, Label::Sunset),
             entry.sunset,
         )
     } else {
-        format!(
-            "{}\n{}~{}°C",
-            &entry.date[5..=9],
-            entry.temp_min,
-            entry.temp_max
-        )
+        format!("{}\n{}", entry.month_day(), range)
     };
 
     let font =
@@ -263,33 +669,63 @@ fn draw_forecast_item(
         display,
     )?;
 
+    // Warming or cooling compared to the day before, after the temperature range
+    if let Some(previous) = previous {
+        let x = base_point.x + 36 + text_width(&font, &range)? + 6;
+        let y = base_point.y + font.get_default_line_height() as i32 + 8;
+        draw_trend_arrow(display, x, y, entry.temp_max - previous.temp_max)?;
+    }
+
     Ok(())
 }
 
-fn draw_common_part(
-    display: &mut Display,
-    weather: &WeatherInfo,
-    now: &OffsetDateTime,
-    sensor: (f32, f32),
-) -> Result<()> {
+/// Draws a small arrow centered on `(x, y)` pointing up for a positive
+/// `delta`, down for a negative one and right when it is zero.
+fn draw_trend_arrow(display: &mut Display, x: i32, y: i32, delta: i32) -> Result<()> {
+    // Unit vector of the arrow and the one perpendicular to it
+    let ((dx, dy), (px, py)) = match delta.signum() {
+        1 => ((0, -1), (1, 0)),
+        -1 => ((0, 1), (1, 0)),
+        _ => ((1, 0), (0, 1)),
+    };
+    let (tip_x, tip_y) = (x + dx * 5, y + dy * 5);
+    display.line(x - dx * 5, y - dy * 5, tip_x, tip_y, Color::Black)?;
+    for side in [-1, 1] {
+        display.line(
+            tip_x,
+            tip_y,
+            tip_x - dx * 3 + px * side * 3,
+            tip_y - dy * 3 + py * side * 3,
+            Color::Black,
+        )?;
+    }
+    Ok(())
+}
+
+fn draw_common_part(display: &mut Display, ctx: &RenderContext) -> Result<()> {
+    let (weather, now, units, lang) = (ctx.weather, ctx.now, ctx.units, ctx.lang);
     let mut base_point = display.bounding_box().top_left;
-    draw_today(display, base_point, now)?;
+    draw_today(display, base_point, now, lang)?;
     base_point += Point::new(128 + 8, 0);
-    draw_top_banner(display, base_point, weather, sensor)?;
+    draw_top_banner(display, base_point, ctx)?;
 
     base_point = display.bounding_box().top_left + Point::new(0, 128 + 8);
-    if weather.daily.is_empty() {
-        return Ok(());
+
+    if ctx.forecast_chart {
+        let days = &weather.daily[..weather.daily.len().min(FORECAST_CHART_DAYS)];
+        return draw_temp_range_chart(display, base_point, days, units);
     }
 
+    // The provider may return fewer than three days, draw what is there
     let mut position = base_point;
-    for idx in [0, 1, 2] {
-        let entry = &weather.daily[idx];
+    for (idx, entry) in weather.daily.iter().take(3).enumerate() {
+        // The first day has nothing to compare against
+        let previous = idx.checked_sub(1).map(|prev| &weather.daily[prev]);
         if idx == 0 {
-            draw_forecast_item(display, position, entry, true)?;
+            draw_forecast_item(display, position, entry, previous, true, ctx)?;
             position += Point::new(0, 80);
         } else {
-            draw_forecast_item(display, position, entry, false)?;
+            draw_forecast_item(display, position, entry, previous, false, ctx)?;
             position += Point::new(0, 40);
         }
         if position.y >= display.bounding_box().size.height as i32 {
@@ -300,110 +736,712 @@ fn draw_common_part(
     Ok(())
 }
 
-fn draw_custom_part(display: &mut Display, content: &str) -> Result<()> {
-    let position = Point::new(128 + 8, (128 + 300) / 2);
-    let font = if content.is_ascii() {
-        FontRenderer::new::<fonts::u8g2_font_courR10_tf>()
+/// Plots the daily highs (red) and lows (black) as two lines in the 128px
+/// wide column below the date block, with the day of month along the bottom.
+/// The vertical scale spans the coldest low to the warmest high.
+fn draw_temp_range_chart(
+    display: &mut Display,
+    base_point: Point,
+    days: &[DailyWeather],
+    units: Units,
+) -> Result<()> {
+    if days.is_empty() {
+        return Ok(());
+    }
+    let temp_min = days
+        .iter()
+        .map(|day| day.temp_min)
+        .min()
+        .unwrap_or_default();
+    let temp_max = days
+        .iter()
+        .map(|day| day.temp_max)
+        .max()
+        .unwrap_or_default();
+    let range = (temp_max - temp_min).max(1);
+
+    // Room for the value labels above and below and the days at the bottom,
+    // which ends above the status line
+    let (left, width) = (base_point.x + 10, 128 - 20);
+    let (top, height) = (base_point.y + 12, 164 - 12 - 42);
+    let step = if days.len() > 1 {
+        width / (days.len() as i32 - 1)
     } else {
-        FontRenderer::new::<fonts::u8g2_font_wqy16_t_gb2312>()
+        0
+    };
+    let point = |idx: usize, temp: i32| {
+        let x = if days.len() > 1 {
+            left + step * idx as i32
+        } else {
+            left + width / 2
+        };
+        Point::new(x, top + height - (temp - temp_min) * height / range)
+    };
+
+    let font = FontRenderer::new::<fonts::u8g2_font_6x10_mf>().with_ignore_unknown_chars(true);
+    for (idx, day) in days.iter().enumerate() {
+        let high = point(idx, day.temp_max);
+        let low = point(idx, day.temp_min);
+        if idx > 0 {
+            let prev_high = point(idx - 1, days[idx - 1].temp_max);
+            let prev_low = point(idx - 1, days[idx - 1].temp_min);
+            display.line(prev_high.x, prev_high.y, high.x, high.y, Color::Red)?;
+            display.line(prev_low.x, prev_low.y, low.x, low.y, Color::Black)?;
+        }
+        display.filled_circle(high.x, high.y, 2, Color::Red)?;
+        display.filled_circle(low.x, low.y, 2, Color::Black)?;
+        font.render_aligned(
+            format!("{}", units.degrees(day.temp_max)).as_str(),
+            high - Point::new(0, 3),
+            VerticalPosition::Bottom,
+            HorizontalAlignment::Center,
+            FontColor::Transparent(Color::Red),
+            display,
+        )?;
+        font.render_aligned(
+            format!("{}", units.degrees(day.temp_min)).as_str(),
+            low + Point::new(0, 4),
+            VerticalPosition::Top,
+            HorizontalAlignment::Center,
+            FontColor::Transparent(Color::Black),
+            display,
+        )?;
+        // Day of month from the YYYY-MM-DD date
+        let day_of_month = day.date.get(8..10).unwrap_or_default();
+        font.render_aligned(
+            day_of_month,
+            Point::new(high.x, top + height + 18),
+            VerticalPosition::Top,
+            HorizontalAlignment::Center,
+            FontColor::Transparent(Color::Black),
+            display,
+        )?;
     }
-    .with_ignore_unknown_chars(true);
+    Ok(())
+}
+
+fn draw_hourly_strip(
+    display: &mut Display,
+    base_point: Point,
+    hourly: &[HourlyWeather],
+    units: Units,
+) -> Result<()> {
+    // QWeather may return fewer than 24 entries, draw whatever is available
+    let entries = &hourly[..hourly.len().min(12)];
+    if entries.len() < 2 {
+        return Ok(());
+    }
+
+    let temp_min = entries
+        .iter()
+        .map(|e| e.temperature)
+        .min()
+        .unwrap_or_default();
+    let temp_max = entries
+        .iter()
+        .map(|e| e.temperature)
+        .max()
+        .unwrap_or_default();
+    let range = (temp_max - temp_min).max(1);
+    let step = 256 / entries.len() as i32;
+
+    let font = FontRenderer::new::<fonts::u8g2_font_6x10_mf>().with_ignore_unknown_chars(true);
+    let mut last_point: Option<Point> = None;
+    for (idx, entry) in entries.iter().enumerate() {
+        let x = base_point.x + step * idx as i32 + step / 2;
+        let y = base_point.y + 12 + (temp_max - entry.temperature) * 16 / range;
+        if let Some(last_point) = last_point {
+            display.line(last_point.x, last_point.y, x, y, Color::Red)?;
+        }
+        display.filled_circle(x, y, 1, Color::Red)?;
+        last_point = Some(Point::new(x, y));
+
+        if entry.precipitation > 0.0 {
+            let height = (entry.precipitation * 2.0).ceil().min(6.0) as u32;
+            Rectangle::new(
+                Point::new(x - 2, base_point.y + 35 - height as i32),
+                Size::new(5, height),
+            )
+            .draw_styled(&PrimitiveStyle::with_fill(Color::Black), display)?;
+        }
+
+        if idx % 3 == 0 {
+            let content = format!("{}", units.degrees(entry.temperature));
+            font.render_aligned(
+                &content as &str,
+                Point::new(x, y - 3),
+                VerticalPosition::Bottom,
+                HorizontalAlignment::Center,
+                FontColor::Transparent(Color::Black),
+                display,
+            )?;
+            font.render_aligned(
+                entry.time.get(11..13).unwrap_or_default(),
+                Point::new(x, base_point.y + 44),
+                VerticalPosition::Bottom,
+                HorizontalAlignment::Center,
+                FontColor::Transparent(Color::Black),
+                display,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn draw_sparkline(
+    display: &mut Display,
+    base_point: Point,
+    values: &[f32],
+    w: u32,
+    h: u32,
+) -> Result<()> {
+    if values.is_empty() || w == 0 || h == 0 {
+        return Ok(());
+    }
+
+    let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let (w, h) = (w as i32, h as i32);
+    let to_point = |idx: usize, value: f32| {
+        let x = if values.len() == 1 {
+            w / 2
+        } else {
+            idx as i32 * (w - 1) / (values.len() as i32 - 1)
+        };
+        // A flat series is drawn through the middle of the box
+        let y = if max - min < f32::EPSILON {
+            h / 2
+        } else {
+            ((max - value) / (max - min) * (h - 1) as f32).round() as i32
+        };
+        base_point + Point::new(x, y)
+    };
+
+    if values.len() == 1 {
+        let point = to_point(0, values[0]);
+        return display.filled_circle(point.x, point.y, 1, Color::Red);
+    }
+    for (idx, pair) in values.windows(2).enumerate() {
+        let from = to_point(idx, pair[0]);
+        let to = to_point(idx + 1, pair[1]);
+        display.line(from.x, from.y, to.x, to.y, Color::Red)?;
+    }
+    Ok(())
+}
+
+/// Size of the pixels `text` would cover when rendered with `font`, without
+/// drawing it. Unlike `text_width` this leaves out the trailing advance.
+fn text_size(font: &FontRenderer, text: &str) -> Result<Size> {
+    let dimensions = font.get_rendered_dimensions(text, Point::zero(), VerticalPosition::Top)?;
+    Ok(dimensions
+        .bounding_box
+        .map_or(Size::zero(), |bounding_box| bounding_box.size))
+}
+
+/// Returns the largest of the large numeric fonts that renders `text` within
+/// `bounds`, falling back to the smallest one. The fonts only cover digits and
+/// a few symbols such as `-`, `.` and `:`.
+fn fit_font(text: &str, bounds: Size) -> Result<FontRenderer> {
+    let candidates = [
+        FontRenderer::new::<fonts::u8g2_font_logisoso46_tn>(),
+        FontRenderer::new::<fonts::u8g2_font_logisoso38_tn>(),
+        FontRenderer::new::<fonts::u8g2_font_logisoso30_tn>(),
+    ];
+    for font in candidates {
+        let font = font.with_ignore_unknown_chars(true);
+        let size = text_size(&font, text)?;
+        if size.width <= bounds.width && size.height <= bounds.height {
+            return Ok(font);
+        }
+    }
+    Ok(FontRenderer::new::<fonts::u8g2_font_logisoso24_tn>().with_ignore_unknown_chars(true))
+}
+
+/// Draws the note into the custom area. A note too long for the area is
+/// split into pages and `page` (any counter) selects which one is shown.
+fn draw_custom_part(display: &mut Display, content: &str, page: usize) -> Result<()> {
+    let font = NoteFont::new(content);
+    let lines = wrap_text(&font, content, 256)?;
+    let line_height = font.line_height;
+    let per_page = (NOTE_HEIGHT / line_height).max(1) as usize;
+    if lines.len() <= per_page {
+        // Center the wrapped note above the sparkline
+        let height = lines.len() as i32 * line_height;
+        let top = ((128 + 8 + 200) / 2 - height / 2).max(128 + 8);
+        draw_wrapped_text(
+            display,
+            &font,
+            content,
+            Point::new(128 + 8, top),
+            256,
+            Color::Red,
+        )?;
+        return Ok(());
+    }
+
+    let pages: Vec<&[String]> = lines.chunks(per_page).collect();
+    let page = page % pages.len();
+    for (idx, line) in pages[page].iter().enumerate() {
+        let position = Point::new(128 + 8, 128 + 8 + line_height * idx as i32);
+        font.render(display, line, position, Color::Red)?;
+    }
+    let indicator = format!("{}/{}", page + 1, pages.len());
+    let font = FontRenderer::new::<fonts::u8g2_font_6x10_mf>().with_ignore_unknown_chars(true);
     font.render_aligned(
-        content,
-        position,
-        VerticalPosition::Center,
-        HorizontalAlignment::Left,
-        FontColor::Transparent(Color::Red),
+        indicator.as_str(),
+        Point::new(128 + 8 + 256, 128 + 8 + NOTE_HEIGHT),
+        VerticalPosition::Bottom,
+        HorizontalAlignment::Right,
+        FontColor::Transparent(Color::Black),
         display,
     )?;
     Ok(())
 }
 
-fn require_refresh(now: &OffsetDateTime) -> bool {
-    if now.minute() != 0 || now.second() != 0 {
+/// The weather dashboard: date, current conditions, forecast, the image or
+/// note (or a QR code of the web interface) and the indoor sparkline.
+struct WeatherPage;
+
+impl Page for WeatherPage {
+    fn render(&self, display: &mut Display, ctx: &RenderContext) -> Result<()> {
+        draw_common_part(display, ctx)?;
+        if let Some(image) = ctx.image {
+            display.bitmap(128 + 8, 136, IMAGE_WIDTH, IMAGE_HEIGHT, image, Color::Black)?;
+        } else if self.shows_note(ctx) {
+            draw_custom_part(display, ctx.note, ctx.note_page)?;
+        } else if let Some(url) = &ctx.url {
+            // Until a note is set, point new users at the web interface
+            draw_qr(display, 128 + 8, 136, url, 2)?;
+        }
+        let recent = &ctx.history[ctx.history.len().saturating_sub(SPARKLINE_LEN)..];
+        let temperature: Vec<f32> = recent.iter().map(|sample| sample.0).collect();
+        draw_sparkline(display, Point::new(128 + 8, 204), &temperature, 256, 28)?;
+        draw_hourly_strip(
+            display,
+            Point::new(128 + 8, 236),
+            &ctx.weather.hourly,
+            ctx.units,
+        )?;
+        Ok(())
+    }
+
+    fn shows_note(&self, ctx: &RenderContext) -> bool {
+        ctx.image.is_none() && !ctx.note.is_empty()
+    }
+}
+
+fn require_refresh(now: &OffsetDateTime, settings: &Settings) -> bool {
+    let minutes = now.hour() as u32 * 60 + now.minute() as u32;
+    if minutes % settings.refresh_interval_minutes.max(1) != 0 || now.second() != 0 {
         return false;
     }
-    match now.hour() {
-        7..=23 => true,
-        _ => false,
+    in_refresh_window(now, settings)
+}
+
+fn in_refresh_window(now: &OffsetDateTime, settings: &Settings) -> bool {
+    let (start, end, hour) = (
+        settings.refresh_start_hour,
+        settings.refresh_end_hour,
+        now.hour(),
+    );
+    if start <= end {
+        (start..=end).contains(&hour)
+    } else {
+        // The window wraps around midnight, e.g. 22 to 6
+        hour >= start || hour <= end
+    }
+}
+
+/// True if `current` differs enough from the weather last drawn to be worth
+/// a refresh before the next scheduled one.
+fn weather_changed(
+    current: &CurrentWeather,
+    drawn: Option<&(String, i32)>,
+    threshold: u32,
+) -> bool {
+    match drawn {
+        Some((text, temperature)) => {
+            current.text != *text || current.temperature.abs_diff(*temperature) > threshold
+        }
+        None => true,
     }
 }
 
 pub fn app_main(
-    mut ssd1683: SSD1683,
-    mut dht20: DHT20,
-    wifi: WifiDevice,
+    mut panel: Box<dyn Device>,
+    sensor: Box<dyn IndoorSensor>,
+    mut battery: Option<Battery>,
+    button: Option<Button>,
+    mut wifi: WifiDevice,
+    nvs: EspDefaultNvsPartition,
     conf: Config,
 ) -> Result<()> {
     let mut httpd = HttpServer::new()?;
+    if !conf.http_username.is_empty() {
+        httpd.set_credentials(conf.http_username, conf.http_password);
+    }
     httpd.add_handlers()?;
-    let mut weather = WeatherInfo::new(conf.location, conf.qweather_key);
+    let lang = Language::from_code(conf.language);
+    let units = Units::from_code(conf.units);
+    let mut settings = Settings::load(&nvs, &conf);
+    httpd.set_settings(&settings)?;
+    let fetcher = WeatherFetcher::new(
+        WeatherInfo::new(Some(nvs.clone())),
+        Box::new(QWeatherProvider::new(
+            &settings.location,
+            &settings.key,
+            lang,
+        )),
+    )?;
+    request_location(&fetcher, &nvs, &conf, &settings);
+    let mut mqtt = if conf.mqtt_host.is_empty() {
+        None
+    } else {
+        Some(MqttPublisher::new(
+            conf.mqtt_host,
+            conf.mqtt_port,
+            conf.mqtt_username,
+            conf.mqtt_password,
+            conf.mqtt_topic,
+        )?)
+    };
     let mut first_draw = true;
-    let mut sensor = dht20.read()?;
+    // Set once a city lookup filled in the location, until the provider
+    // is switched to it
+    let mut location_resolved = false;
+    // SNTP keeps retrying in the background, this notices when it succeeds
+    let mut clock_synced = false;
+    // Set while a refresh waits for the weather it requested
+    let mut awaiting_weather = false;
+    // Set while a change check waits for the weather, which is only drawn
+    // if it differs enough from `drawn_weather`
+    let mut checking_weather = false;
+    let mut drawn_weather: Option<(String, i32)> = None;
+    // Set while the battery is low, refreshes stop until it recovers
+    let mut battery_low = false;
+    let mut indoor = Smoothed::new(sensor, SENSOR_SMOOTHING_WINDOW);
+    // None while the sensor is missing, the rest of the screen still works
+    let mut reading: Option<Reading> = None;
+    let mut history = VecDeque::new();
+    let pages: Vec<Box<dyn Page>> = vec![Box::new(WeatherPage), Box::new(ChartPage)];
+    let (mut page_index, mut page_refreshes) = (0, 0);
+    // Fast refreshes since the last full one, starting out due so the first
+    // refresh after boot is a full one
+    let mut fast_refreshes = conf.full_refresh_every;
+    // Advances on every refresh, restarting when the note changes
+    let (mut note_page, mut shown_note) = (0, String::new());
+    // Set while a flash message is on the panel, it is redrawn once expired
+    let mut showing_flash = false;
+    let watchdog = if conf.watchdog_timeout_secs > 0 {
+        Some(Watchdog::new(conf.watchdog_timeout_secs)?)
+    } else {
+        None
+    };
     loop {
-        let now = now_localtime();
-        if now.second() == 0 && now.minute() % 5 == 0 {
-            sensor = dht20.read()?;
-            httpd.add_sensor_data(now, sensor)?;
+        if let Some(watchdog) = &watchdog {
+            watchdog.feed();
+        }
+        if let Err(err) = wifi.ensure_connected() {
+            log::push(format!("Wi-Fi reconnect failed: {}", err));
+        }
+        let now = now_localtime(&settings);
+        let sample = first_draw || (now.second() == 0 && now.minute() % 5 == 0);
+        let mut battery_recovered = false;
+        if let Some(battery) = battery.as_mut().filter(|_| sample) {
+            let threshold = conf.low_battery_millivolts;
+            match battery.read_voltage() {
+                Ok(voltage) if threshold > 0 && !battery_low => {
+                    if ((voltage * 1000.0) as u32) < threshold {
+                        log::push(format!("Battery low at {:.2}V, refreshes paused", voltage));
+                        battery_low = true;
+                        first_draw = false;
+                        awaiting_weather = false;
+                        checking_weather = false;
+                        let mut display = Display::new(
+                            conf.screen_width as usize,
+                            conf.screen_height as usize,
+                            Color::White,
+                        );
+                        display.clear(Color::White);
+                        draw_low_battery(&mut display, voltage, lang)?;
+                        if conf.invert_colors {
+                            display.invert();
+                        }
+                        // A full refresh so the warning is crisp for as long as it stays
+                        match panel.draw(&display, false) {
+                            Ok(()) => fast_refreshes = 0,
+                            Err(err) => log::push(format!("Failed to refresh display: {}", err)),
+                        }
+                        httpd.set_screen(display)?;
+                    }
+                }
+                Ok(voltage) if battery_low => {
+                    if (voltage * 1000.0) as u32 >= threshold + LOW_BATTERY_HYSTERESIS_MV {
+                        log::push(format!("Battery at {:.2}V, refreshes resumed", voltage));
+                        battery_low = false;
+                        battery_recovered = true;
+                        fast_refreshes = conf.full_refresh_every;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => log::push(format!("Failed to read battery: {}", err)),
+            }
+        }
+        if sample {
+            match indoor.read() {
+                Ok(fresh) => {
+                    reading = Some(fresh);
+                    let sensor = (fresh.temperature, fresh.humidity);
+                    httpd.add_sensor_data(now, sensor)?;
+                    if history.len() == HISTORY_LEN {
+                        history.pop_front();
+                    }
+                    history.push_back(sensor);
+                    if let Some(mqtt) = mqtt.as_mut() {
+                        if let Err(err) = mqtt.publish_sensor(sensor) {
+                            println!("Failed to publish sensor data: {}", err);
+                        }
+                    }
+                }
+                Err(WmError::SensorMissing) => {
+                    log::push("Indoor sensor not found, showing N/A");
+                    reading = None;
+                }
+                // A bad CRC or the like, keep showing the previous reading
+                Err(err) => log::push(format!("Failed to read indoor sensor: {}", err)),
+            }
+        }
+        let sensor = reading.map(|reading| (reading.temperature, reading.humidity));
+        // Until SNTP succeeds the clock sits near the UNIX epoch
+        let synced = wifi.time_synced();
+        // Redraw fully once a late sync replaces the placeholders with the time
+        let clock_fixed = synced && !clock_synced;
+        if clock_fixed && !first_draw {
+            log::push("Clock synchronized, redrawing");
+            fast_refreshes = conf.full_refresh_every;
+        }
+        clock_synced = synced;
+        let (weather_age, weather_updated) = {
+            let weather = fetcher.weather();
+            let updated = Some(now)
+                .filter(|_| synced)
+                .and_then(|now| weather.last_update(now));
+            (weather.age(), updated)
+        };
+        httpd.set_status(StatusReport::new(
+            wifi.rssi().ok(),
+            wifi.reconnect_count(),
+            weather_age,
+            weather_updated,
+            sensor,
+        ))?;
+        // Both requests are consumed so one press doesn't cause two redraws
+        let button_pressed = button.as_ref().map_or(false, Button::take_press);
+        let refresh_requested = httpd.get_refresh_flag()?;
+        let scheduled = synced && require_refresh(&now, &settings);
+        // A redraw already on its way drops the expired flash by itself
+        let flash_expired =
+            showing_flash && !awaiting_weather && httpd.get_flash_message()?.is_none();
+        if flash_expired {
+            // Cleared here, not after the redraw, so the expiry triggers it once
+            showing_flash = false;
         }
-        if first_draw || httpd.get_refresh_flag()? || require_refresh(&now) {
+        // A city lookup finished on the fetch thread, ignored if a location
+        // was set in the meantime
+        if let Some(id) = fetcher.take_resolved() {
+            if settings.location.is_empty() {
+                settings.location = id;
+                location_resolved = true;
+            }
+        }
+        let triggered = first_draw
+            || button_pressed
+            || refresh_requested
+            || scheduled
+            || clock_fixed
+            || flash_expired
+            || battery_recovered
+            || location_resolved;
+        if triggered && !battery_low {
             first_draw = false;
-            weather.try_update();
-            let content: String = httpd.get_note_content()?;
-            let mut display = Display::new(400, 300, Color::White);
+            let reset = httpd.take_factory_reset()?;
+            if reset {
+                if let Err(err) = Settings::clear(&nvs) {
+                    println!("Failed to clear settings: {}", err);
+                }
+                // The cached weather and city lookup may belong to settings
+                // that no longer apply
+                if let Err(err) = fetcher.weather().forget_saved() {
+                    println!("Failed to clear saved weather: {}", err);
+                }
+                if let Err(err) = forget_city(&nvs) {
+                    println!("Failed to clear cached city: {}", err);
+                }
+                settings = Settings::from_config(&conf);
+            }
+            let mut changed = false;
+            if let Some(updated) = httpd.take_settings()? {
+                settings = updated;
+                changed = true;
+            }
+            if let Some((location, key)) = httpd.take_weather_config()? {
+                if !key.is_empty() {
+                    settings.key = key;
+                }
+                settings.location = location;
+                changed = true;
+            }
+            if changed {
+                if let Err(err) = settings.save(&nvs) {
+                    println!("Failed to save settings: {}", err);
+                }
+            }
+            // Retried on every refresh until the lookup succeeds, and again
+            // after a factory reset dropped the location
+            request_location(&fetcher, &nvs, &conf, &settings);
+            if reset || changed || location_resolved {
+                location_resolved = false;
+                httpd.set_settings(&settings)?;
+                fetcher.set_provider(Box::new(QWeatherProvider::new(
+                    &settings.location,
+                    &settings.key,
+                    lang,
+                )));
+            }
+            fetcher.request_update();
+            awaiting_weather = true;
+        } else if conf.weather_change_threshold > 0
+            && !battery_low
+            && synced
+            && !awaiting_weather
+            && now.second() == 0
+            && now.minute() % WEATHER_CHECK_MINUTES == 0
+            && in_refresh_window(&now, &settings)
+        {
+            fetcher.request_update();
+            checking_weather = true;
+        }
+        // The loop keeps polling while the weather is fetched in the background
+        let finished = (awaiting_weather || checking_weather) && fetcher.take_finished();
+        let changed = finished && !awaiting_weather && {
+            let weather = fetcher.weather();
+            weather.valid
+                && weather_changed(
+                    &weather.now,
+                    drawn_weather.as_ref(),
+                    conf.weather_change_threshold,
+                )
+        };
+        if finished {
+            checking_weather = false;
+        }
+        if changed {
+            log::push("Weather changed, redrawing");
+        }
+        if finished && (awaiting_weather || changed) {
+            awaiting_weather = false;
+            // A flash message takes the place of the note and the image
+            let flash = httpd.get_flash_message()?;
+            showing_flash = flash.is_some();
+            let image = match flash {
+                Some(_) => None,
+                None => httpd.get_image()?,
+            };
+            let note = match flash {
+                Some(flash) => flash,
+                None => httpd.get_note_content()?,
+            };
+            if note != shown_note {
+                note_page = 0;
+                shown_note = note.clone();
+            }
+            let mut display = Display::new(
+                conf.screen_width as usize,
+                conf.screen_height as usize,
+                Color::White,
+            );
             display.clear(Color::White);
-            draw_common_part(&mut display, &weather, &now, sensor)?;
-            draw_custom_part(&mut display, &content)?;
-            show_status(&mut display, &wifi, &now)?;
-            ssd1683.draw(&display, false)?;
+            let weather = fetcher.weather();
+            let ctx = RenderContext {
+                weather: &weather,
+                now: Some(&now).filter(|_| synced),
+                sensor,
+                pressure: reading.and_then(|reading| reading.pressure),
+                history: history.make_contiguous(),
+                note: &note,
+                note_page,
+                image: image.as_deref(),
+                air_details: conf.air_details,
+                forecast_chart: conf.forecast_chart,
+                icon_threshold: conf.icon_threshold as usize,
+                units,
+                url: wifi.ip_addr().ok().map(|ip| format!("http://{}/", ip)),
+                lang,
+            };
+            pages[page_index].render(&mut display, &ctx)?;
+            draw_alert_banner(&mut display, &weather.alerts)?;
+            if pages[page_index].shows_note(&ctx) {
+                note_page += 1;
+            }
+            page_refreshes += 1;
+            if page_refreshes >= PAGE_REFRESHES {
+                page_refreshes = 0;
+                page_index = (page_index + 1) % pages.len();
+            }
+            let battery_level = match battery.as_mut().map(Battery::read_percentage) {
+                Some(Ok(level)) => Some(level),
+                Some(Err(err)) => {
+                    println!("Failed to read battery: {}", err);
+                    None
+                }
+                None => None,
+            };
+            show_status(&mut display, &wifi, battery_level, &weather, ctx.now, lang)?;
+            if weather.valid {
+                drawn_weather = Some((weather.now.text.clone(), weather.now.temperature));
+            }
+            // Don't keep the fetch thread waiting during the slow panel refresh
+            drop(weather);
+            if conf.invert_colors {
+                display.invert();
+            }
+            let fast =
+                panel.supports_fast() && fast_refreshes.saturating_add(1) < conf.full_refresh_every;
+            match panel.draw(&display, fast) {
+                Ok(()) if fast => fast_refreshes += 1,
+                Ok(()) => fast_refreshes = 0,
+                Err(err) => log::push(format!("Failed to refresh display: {}", err)),
+            }
+            httpd.set_screen(display)?;
         }
         sleep(Duration::from_secs(1));
     }
 }
 
-fn weekday_to_string(weekday: Weekday) -> &'static str {
-    match weekday {
-        Weekday::Monday => "星期一",
-        Weekday::Tuesday => "星期二",
-        Weekday::Wednesday => "星期三",
-        Weekday::Thursday => "星期四",
-        Weekday::Friday => "星期五",
-        Weekday::Saturday => "星期六",
-        Weekday::Sunday => "星期日",
+/// A city name stands in for the LocationID when none is configured. The
+/// lookup runs on the fetch thread so a slow GeoAPI doesn't stall the loop,
+/// the result arrives through `WeatherFetcher::take_resolved`.
+fn request_location(
+    fetcher: &WeatherFetcher,
+    nvs: &EspDefaultNvsPartition,
+    conf: &Config,
+    settings: &Settings,
+) {
+    if settings.location.is_empty() && !conf.city.is_empty() {
+        fetcher.resolve_city(nvs.clone(), conf.city, &settings.key);
     }
 }
 
-fn now_localtime() -> OffsetDateTime {
-    time::OffsetDateTime::now_utc().to_offset(offset!(+8))
+fn now_localtime(settings: &Settings) -> OffsetDateTime {
+    time::OffsetDateTime::now_utc().to_offset(settings.utc_offset())
 }
 
-fn get_bit(image: &[u8], size: usize, i: usize, j: usize) -> u8 {
-    let pos = i * size + j;
-    if image[pos / 8] & (1u8 << (7 - (pos % 8) as u8)) != 0 {
-        1
-    } else {
-        0
-    }
-}
-
-fn build_32x32_icon(code: i32) -> Vec<u8> {
-    if let Some(image) = extract_icon(code) {
-        let mut new_image = Vec::new();
-        new_image.resize(32 * 32 / 8, 0);
-        for i in 0..32 {
-            for j in 0..32 {
-                let val = get_bit(image, 64, i * 2, j * 2)
-                    + get_bit(image, 64, i * 2 + 1, j * 2)
-                    + get_bit(image, 64, i * 2, j * 2 + 1)
-                    + get_bit(image, 64, i * 2 + 1, j * 2 + 1);
-                if val > 2 {
-                    let pos = i * 32 + j;
-                    new_image[pos / 8] |= 1u8 << (7 - (pos % 8) as u8);
-                }
-            }
-        }
-        new_image
-    } else {
-        Vec::new()
-    }
+/// Halves a 64px icon. By default three of the four source pixels must be
+/// set, which keeps thin strokes from smearing into each other; a lower
+/// `threshold` favours ink so thin lines survive.
+fn build_32x32_icon(code: i32, threshold: usize) -> Vec<u8> {
+    extract_icon(code)
+        .and_then(|image| Display::downscale(image, 64, 64, 2, threshold.clamp(1, 4)).ok())
+        .unwrap_or_default()
 }
@@ -0,0 +1,144 @@
+use super::page::{Page, RenderContext};
+use super::strings::{label, Label};
+use crate::display::{Color, Display};
+use crate::error::Result;
+
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, StyledDrawable};
+use u8g2_fonts::{fonts, types::*, FontRenderer};
+
+const MARGIN_X: i32 = 36;
+const MARGIN_TOP: i32 = 24;
+// Leaves room for the status line at the bottom
+const MARGIN_BOTTOM: i32 = 16;
+
+/// Full screen chart of the indoor temperature (red, left scale) and
+/// humidity (black, right scale) history.
+pub struct ChartPage;
+
+impl Page for ChartPage {
+    fn render(&self, display: &mut Display, ctx: &RenderContext) -> Result<()> {
+        let size = display.size();
+        let area = Rectangle::new(
+            Point::new(MARGIN_X, MARGIN_TOP),
+            Size::new(
+                (size.width as i32 - 2 * MARGIN_X).max(2) as u32,
+                (size.height as i32 - MARGIN_TOP - MARGIN_BOTTOM).max(2) as u32,
+            ),
+        );
+        area.draw_styled(&PrimitiveStyle::with_stroke(Color::Black, 1), display)?;
+
+        let font =
+            FontRenderer::new::<fonts::u8g2_font_wqy12_t_gb2312a>().with_ignore_unknown_chars(true);
+        let mut title = match ctx.sensor {
+            Some((temperature, humidity)) => format!(
+                "{} {:.1}{} | {:.1}%",
+                label(ctx.lang, Label::IndoorHistory),
+                ctx.units.temperature(temperature),
+                ctx.units.temperature_symbol(),
+                humidity
+            ),
+            None => format!("{} N/A", label(ctx.lang, Label::IndoorHistory)),
+        };
+        if let Some(pressure) = ctx.pressure {
+            title.push_str(&format!(
+                " | {}{}",
+                ctx.units.pressure(pressure),
+                ctx.units.pressure_symbol()
+            ));
+        }
+        font.render_aligned(
+            title.as_str(),
+            Point::new(size.width as i32 / 2, MARGIN_TOP / 2),
+            VerticalPosition::Center,
+            HorizontalAlignment::Center,
+            FontColor::Transparent(Color::Black),
+            display,
+        )?;
+
+        let temperature: Vec<f32> = ctx
+            .history
+            .iter()
+            .map(|sample| ctx.units.temperature(sample.0))
+            .collect();
+        let humidity: Vec<f32> = ctx.history.iter().map(|sample| sample.1).collect();
+        let scale_font =
+            FontRenderer::new::<fonts::u8g2_font_6x10_mf>().with_ignore_unknown_chars(true);
+        if let Some((min, max)) = plot(display, &area, &temperature, Color::Red)? {
+            draw_scale(display, &scale_font, &area, min, max, true, Color::Red)?;
+        }
+        if let Some((min, max)) = plot(display, &area, &humidity, Color::Black)? {
+            draw_scale(display, &scale_font, &area, min, max, false, Color::Black)?;
+        }
+        Ok(())
+    }
+}
+
+/// Plots `values` across `area` scaled to their own range, which is returned.
+fn plot(
+    display: &mut Display,
+    area: &Rectangle,
+    values: &[f32],
+    color: Color,
+) -> Result<Option<(f32, f32)>> {
+    if values.len() < 2 {
+        return Ok(None);
+    }
+    let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    // Keep flat series off the frame and avoid dividing by zero
+    let (min, max) = if max - min < 1.0 {
+        (min - 0.5, max + 0.5)
+    } else {
+        (min, max)
+    };
+
+    let (w, h) = (area.size.width as i32 - 1, area.size.height as i32 - 1);
+    let to_point = |idx: usize, value: f32| {
+        let x = idx as i32 * w / (values.len() as i32 - 1);
+        let y = ((max - value) / (max - min) * h as f32).round() as i32;
+        area.top_left + Point::new(x, y)
+    };
+    for (idx, pair) in values.windows(2).enumerate() {
+        let from = to_point(idx, pair[0]);
+        let to = to_point(idx + 1, pair[1]);
+        display.line(from.x, from.y, to.x, to.y, color)?;
+    }
+    Ok(Some((min, max)))
+}
+
+/// Labels the top and bottom of the range on the left or right side.
+fn draw_scale(
+    display: &mut Display,
+    font: &FontRenderer,
+    area: &Rectangle,
+    min: f32,
+    max: f32,
+    left: bool,
+    color: Color,
+) -> Result<()> {
+    let (x, alignment) = if left {
+        (area.top_left.x - 3, HorizontalAlignment::Right)
+    } else {
+        (
+            area.top_left.x + area.size.width as i32 + 3,
+            HorizontalAlignment::Left,
+        )
+    };
+    let bottom = area.top_left.y + area.size.height as i32 - 1;
+    for (value, y, vertical) in [
+        (max, area.top_left.y, VerticalPosition::Top),
+        (min, bottom, VerticalPosition::Bottom),
+    ] {
+        let content = format!("{:.1}", value);
+        font.render_aligned(
+            content.as_str(),
+            Point::new(x, y),
+            vertical,
+            alignment,
+            FontColor::Transparent(color),
+            display,
+        )?;
+    }
+    Ok(())
+}
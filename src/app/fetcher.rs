@@ -0,0 +1,135 @@
+use super::qweather::resolve_city;
+use super::weather::{WeatherInfo, WeatherProvider, WeatherUpdate};
+use crate::error::Result;
+use crate::log;
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+// The requests run mbedTLS on the fetch thread, and the TLS handshake alone
+// overflows the 3KB pthread default. Matches CONFIG_ESP_MAIN_TASK_STACK_SIZE,
+// which is what the fetches ran with on the main task.
+const FETCH_STACK_SIZE: usize = 20000;
+
+enum Request {
+    Update,
+    SetProvider(Box<dyn WeatherProvider>),
+    ResolveCity {
+        nvs: EspDefaultNvsPartition,
+        city: String,
+        key: String,
+    },
+}
+
+/// Fetches the weather on a background thread so a slow or timing out
+/// request doesn't stall the app loop. The shared `WeatherInfo` is only
+/// locked while results are applied, never during the requests.
+pub struct WeatherFetcher {
+    info: Arc<Mutex<WeatherInfo>>,
+    requests: Sender<Request>,
+    finished: Arc<AtomicBool>,
+    // Set from queuing a city lookup until it is done
+    resolving: Arc<AtomicBool>,
+    resolved: Arc<Mutex<Option<String>>>,
+}
+
+impl WeatherFetcher {
+    pub fn new(info: WeatherInfo, provider: Box<dyn WeatherProvider>) -> Result<Self> {
+        let info = Arc::new(Mutex::new(info));
+        let finished = Arc::new(AtomicBool::new(false));
+        let (requests, receiver) = mpsc::channel();
+
+        let resolving = Arc::new(AtomicBool::new(false));
+        let resolved = Arc::new(Mutex::new(None));
+
+        let shared = Arc::clone(&info);
+        let flag = Arc::clone(&finished);
+        let lookup = Arc::clone(&resolving);
+        let location = Arc::clone(&resolved);
+        let mut provider = provider;
+        thread::Builder::new()
+            .stack_size(FETCH_STACK_SIZE)
+            .spawn(move || {
+                for request in receiver {
+                    match request {
+                        Request::SetProvider(next) => {
+                            provider = next;
+                            shared.lock().unwrap().reset_backoff();
+                        }
+                        Request::Update => {
+                            if shared.lock().unwrap().is_due() {
+                                let update = WeatherUpdate::fetch(provider.as_mut());
+                                shared.lock().unwrap().apply(update);
+                            }
+                            flag.store(true, Ordering::SeqCst);
+                        }
+                        Request::ResolveCity { nvs, city, key } => {
+                            match resolve_city(&nvs, &city, &key) {
+                                Ok(id) => {
+                                    log::push(format!("Resolved {} to location {}", city, id));
+                                    *location.lock().unwrap() = Some(id);
+                                }
+                                Err(err) => {
+                                    log::push(format!("Failed to resolve {}: {}", city, err))
+                                }
+                            }
+                            lookup.store(false, Ordering::SeqCst);
+                        }
+                    }
+                }
+            })?;
+        Ok(WeatherFetcher {
+            info,
+            requests,
+            finished,
+            resolving,
+            resolved,
+        })
+    }
+
+    /// Queues an update, which is skipped while backing off after failures.
+    /// Clears the finished flag so `take_finished` waits for this request
+    /// rather than an earlier one.
+    pub fn request_update(&self) {
+        self.finished.store(false, Ordering::SeqCst);
+        let _ = self.requests.send(Request::Update);
+    }
+
+    /// Switches to a new provider, e.g. after the location changed, and
+    /// clears any pending backoff. Applies to the next requested update.
+    pub fn set_provider(&self, provider: Box<dyn WeatherProvider>) {
+        let _ = self.requests.send(Request::SetProvider(provider));
+    }
+
+    /// Queues a GeoAPI lookup of the LocationID for `city`, unless one is
+    /// still pending. The result is picked up with `take_resolved`.
+    pub fn resolve_city(&self, nvs: EspDefaultNvsPartition, city: &str, key: &str) {
+        if self.resolving.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let _ = self.requests.send(Request::ResolveCity {
+            nvs,
+            city: city.into(),
+            key: key.into(),
+        });
+    }
+
+    /// Returns the LocationID found by the last successful lookup, once.
+    pub fn take_resolved(&self) -> Option<String> {
+        self.resolved.lock().unwrap().take()
+    }
+
+    /// Returns true once after a requested update was handled, whether it
+    /// succeeded, failed or was skipped.
+    pub fn take_finished(&self) -> bool {
+        self.finished.swap(false, Ordering::SeqCst)
+    }
+
+    /// The latest weather. Only waits while a fetch result is being applied.
+    pub fn weather(&self) -> MutexGuard<'_, WeatherInfo> {
+        self.info.lock().unwrap()
+    }
+}
@@ -1,5 +1,20 @@
+#[cfg(feature = "esp")]
 mod app;
+#[cfg(feature = "esp")]
+mod chart;
+#[cfg(feature = "esp")]
+mod fetcher;
+mod moon;
+#[cfg(feature = "esp")]
+mod page;
+mod qr;
+mod qweather;
+mod strings;
+mod text;
+mod units;
 mod weather;
 mod weather_icons;
+mod wind;
 
+#[cfg(feature = "esp")]
 pub use app::app_main;
@@ -0,0 +1,91 @@
+use crate::display::{Color, Display};
+use crate::error::Result;
+
+use std::f32::consts::PI;
+use time::OffsetDateTime;
+
+const SYNODIC_MONTH: f64 = 29.530588853;
+// Julian date of the new moon on 2000-01-06 18:14 UTC
+const REFERENCE_NEW_MOON: f64 = 2451550.26;
+const UNIX_EPOCH_JULIAN_DATE: f64 = 2440587.5;
+
+/// Returns the moon phase in 0.0..1.0, where 0.0 is the new moon, 0.5 the
+/// full moon and values approaching 1.0 the waning crescent.
+pub fn moon_phase(date: OffsetDateTime) -> f32 {
+    let julian_date = date.unix_timestamp() as f64 / 86400.0 + UNIX_EPOCH_JULIAN_DATE;
+    let age = (julian_date - REFERENCE_NEW_MOON).rem_euclid(SYNODIC_MONTH);
+    (age / SYNODIC_MONTH) as f32
+}
+
+/// Draws the moon outline in `outline` and fills the illuminated part in
+/// `Color::Black`, waxing from the right and waning from the left.
+pub fn draw_moon(
+    display: &mut Display,
+    x: i32,
+    y: i32,
+    radius: i32,
+    phase: f32,
+    outline: Color,
+) -> Result<()> {
+    let terminator = (2.0 * PI * phase).cos();
+    for dy in -radius..=radius {
+        let half_width = ((radius * radius - dy * dy) as f32).sqrt();
+        let edge = (half_width * terminator).round() as i32;
+        let half_width = half_width.round() as i32;
+        let (x0, x1) = if phase < 0.5 {
+            (edge, half_width)
+        } else {
+            (-half_width, -edge)
+        };
+        if x0 < x1 {
+            display.line(x + x0, y + dy, x + x1, y + dy, Color::Black)?;
+        }
+    }
+    display.circle(x, y, radius, outline)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phase_at(timestamp: i64) -> f32 {
+        moon_phase(OffsetDateTime::from_unix_timestamp(timestamp).unwrap())
+    }
+
+    // The true moon runs up to about 14 hours ahead of or behind the mean
+    // synodic month, which is half a day or 0.02 of a cycle
+    const TOLERANCE: f32 = 0.03;
+
+    #[test]
+    fn new_moons() {
+        // 2000-01-06 18:14, 2023-12-12 23:32 and 2024-01-11 11:57 UTC
+        for timestamp in [947182440, 1702423920, 1704974220] {
+            let phase = phase_at(timestamp);
+            assert!(
+                phase.min(1.0 - phase) < TOLERANCE,
+                "{}: {}",
+                timestamp,
+                phase
+            );
+        }
+    }
+
+    #[test]
+    fn full_moons() {
+        // 2024-01-25 17:54 and 2025-03-14 06:55 UTC
+        for timestamp in [1706205240, 1741935300] {
+            let phase = phase_at(timestamp);
+            assert!((phase - 0.5).abs() < TOLERANCE, "{}: {}", timestamp, phase);
+        }
+    }
+
+    #[test]
+    fn phase_stays_in_range() {
+        // Before the reference new moon the age must not go negative
+        for timestamp in [0, 946684800, 1704974220 + 20 * 86400] {
+            let phase = phase_at(timestamp);
+            assert!((0.0..1.0).contains(&phase), "{}: {}", timestamp, phase);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use super::strings::Language;
+use super::units::Units;
+use super::weather::WeatherInfo;
+use crate::display::Display;
+use crate::error::Result;
+
+use time::OffsetDateTime;
+
+/// Everything a page may draw, collected once per refresh by `app_main`.
+pub struct RenderContext<'a> {
+    pub weather: &'a WeatherInfo,
+    // None until the clock is synchronized
+    pub now: Option<&'a OffsetDateTime>,
+    // Indoor (temperature, humidity), None while the sensor is missing
+    pub sensor: Option<(f32, f32)>,
+    // Indoor pressure in hPa, None when the sensor has no barometer
+    pub pressure: Option<f32>,
+    // Indoor (temperature, humidity) samples, oldest first
+    pub history: &'a [(f32, f32)],
+    pub note: &'a str,
+    // Selects the part of a note too long for one screen
+    pub note_page: usize,
+    // Bitmap posted to `/image`, shown in place of the note
+    pub image: Option<&'a [u8]>,
+    pub url: Option<String>,
+    // Show SO2, NO2, CO and O3 in the banner instead of the other readings
+    pub air_details: bool,
+    // Plot the week's highs and lows instead of the forecast items
+    pub forecast_chart: bool,
+    // Set pixels out of four needed to keep a pixel of a downscaled icon
+    pub icon_threshold: usize,
+    pub units: Units,
+    pub lang: Language,
+}
+
+/// One screen layout. `app_main` cycles through its pages on full refreshes;
+/// the status line is drawn on top of every page.
+pub trait Page {
+    fn render(&self, display: &mut Display, ctx: &RenderContext) -> Result<()>;
+
+    /// Whether `render` draws the note, only then does the note page advance.
+    fn shows_note(&self, _ctx: &RenderContext) -> bool {
+        false
+    }
+}
@@ -0,0 +1,34 @@
+use crate::display::{Color, Display};
+use crate::error::{Result, WmError};
+
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, StyledDrawable};
+use qrcodegen::{QrCode, QrCodeEcc};
+
+// Scanners need a light margin of a few modules around the symbol
+const QUIET_ZONE: i32 = 2;
+
+/// Draws `data` as a QR code with its top left corner (including the quiet
+/// zone) at `(x, y)`, each module being `scale` pixels wide. Returns the
+/// side length in pixels.
+pub fn draw_qr(display: &mut Display, x: i32, y: i32, data: &str, scale: u32) -> Result<u32> {
+    let qr = QrCode::encode_text(data, QrCodeEcc::Low).map_err(|_| WmError::InvalidArgument)?;
+    let scale = scale.max(1);
+    let side = (qr.size() + 2 * QUIET_ZONE) as u32 * scale;
+    Rectangle::new(Point::new(x, y), Size::new(side, side))
+        .draw_styled(&PrimitiveStyle::with_fill(Color::White), display)?;
+
+    let module = PrimitiveStyle::with_fill(Color::Black);
+    for row in 0..qr.size() {
+        for col in 0..qr.size() {
+            if qr.get_module(col, row) {
+                let top_left = Point::new(
+                    x + (col + QUIET_ZONE) * scale as i32,
+                    y + (row + QUIET_ZONE) * scale as i32,
+                );
+                Rectangle::new(top_left, Size::new(scale, scale)).draw_styled(&module, display)?;
+            }
+        }
+    }
+    Ok(side)
+}
@@ -0,0 +1,469 @@
+use super::strings::Language;
+use super::weather::{CurrentWeather, DailyWeather, HourlyWeather, WeatherAlert, WeatherProvider};
+use crate::error::{Result, WmError};
+#[cfg(feature = "esp")]
+use crate::network::form::url_encode;
+#[cfg(feature = "esp")]
+use crate::network::http::HttpClient;
+use crate::network::HttpGet;
+#[cfg(feature = "esp")]
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+use serde_json::Value;
+
+const GEO_NVS_NAMESPACE: &str = "geo";
+const GEO_NVS_KEY: &str = "city";
+const GEO_NVS_MAX_SIZE: usize = 256;
+// Candidates listed when a city name is ambiguous
+const GEO_MAX_CANDIDATES: usize = 5;
+
+fn get_json_map(client: &mut dyn HttpGet, url: &str, key: &str) -> Result<Map<String, Value>> {
+    let result = client.get(url)?;
+    let parsed: Value = serde_json::from_str(&result)?;
+    let now = parsed[key].as_object();
+    if let Some(now) = now {
+        Ok(now.clone())
+    } else {
+        Err(WmError::InvalidArgument)
+    }
+}
+
+fn get_json_vector(client: &mut dyn HttpGet, url: &str, key: &str) -> Result<Vec<Value>> {
+    let result = client.get(url)?;
+    let parsed: Value = serde_json::from_str(&result)?;
+    let now = parsed[key].as_array();
+    if let Some(now) = now {
+        Ok(now.clone())
+    } else {
+        Err(WmError::InvalidArgument)
+    }
+}
+
+macro_rules! json_str {
+    ($entry:expr, $item:literal) => {{
+        let v = $entry.get($item);
+        if let Some(v) = v {
+            String::from(v.as_str().unwrap_or_default())
+        } else {
+            String::from("")
+        }
+    }};
+}
+
+/// QWeather sends numbers as strings, other services as JSON numbers.
+fn value_i32(v: &Value) -> Option<i32> {
+    match v.as_str() {
+        Some(v) => v.trim().parse::<i32>().ok(),
+        None => v
+            .as_i64()
+            .or_else(|| v.as_f64().map(|v| v.round() as i64))
+            .map(|v| v as i32),
+    }
+}
+
+fn value_f32(v: &Value) -> Option<f32> {
+    match v.as_str() {
+        Some(v) => v.trim().parse::<f32>().ok(),
+        None => v.as_f64().map(|v| v as f32),
+    }
+}
+
+macro_rules! json_i32 {
+    ($entry:expr, $item:literal) => {{
+        $entry.get($item).and_then(value_i32).unwrap_or_default()
+    }};
+}
+
+macro_rules! json_f32 {
+    ($entry:expr, $item:literal) => {{
+        $entry.get($item).and_then(value_f32).unwrap_or_default()
+    }};
+}
+
+/// A city name resolved to its QWeather LocationID, cached in NVS.
+#[cfg(feature = "esp")]
+#[derive(Serialize, Deserialize)]
+struct CachedLocation {
+    city: String,
+    id: String,
+}
+
+/// Returns the LocationID for `city`, asking the GeoAPI only when the cached
+/// lookup in NVS was done for a different name.
+#[cfg(feature = "esp")]
+pub fn resolve_city(nvs: &EspDefaultNvsPartition, city: &str, key: &str) -> Result<String> {
+    let mut storage = EspDefaultNvs::new(nvs.clone(), GEO_NVS_NAMESPACE, true)?;
+    let mut buf = Vec::new();
+    buf.resize(GEO_NVS_MAX_SIZE, 0);
+    if let Ok(Some(blob)) = storage.get_raw(GEO_NVS_KEY, &mut buf) {
+        if let Ok(cached) = serde_json::from_slice::<CachedLocation>(blob) {
+            if cached.city == city {
+                return Ok(cached.id);
+            }
+        }
+    }
+
+    let id = lookup_city(city, key)?;
+    let blob = serde_json::to_vec(&CachedLocation {
+        city: city.into(),
+        id: id.clone(),
+    })?;
+    if blob.len() <= GEO_NVS_MAX_SIZE {
+        storage.set_raw(GEO_NVS_KEY, &blob)?;
+    }
+    Ok(id)
+}
+
+/// Drops the cached lookup, so the next `resolve_city` asks the GeoAPI.
+#[cfg(feature = "esp")]
+pub fn forget_city(nvs: &EspDefaultNvsPartition) -> Result<()> {
+    let mut storage = EspDefaultNvs::new(nvs.clone(), GEO_NVS_NAMESPACE, true)?;
+    storage.remove(GEO_NVS_KEY)?;
+    Ok(())
+}
+
+/// Picks the match whose name equals `city`, or the only result. Several
+/// candidates without a single exact match are reported as an error, so
+/// the wrong city is never shown silently.
+#[cfg(feature = "esp")]
+fn lookup_city(city: &str, key: &str) -> Result<String> {
+    // Names come back in the language of the query so they can be compared
+    let lang = if city.is_ascii() { "en" } else { "zh" };
+    let url = format!(
+        "https://geoapi.qweather.com/v2/city/lookup?location={}&key={}&lang={}",
+        url_encode(city),
+        key,
+        lang
+    );
+    let mut client = HttpClient::new()?;
+    let result = client.get(&url)?;
+    let parsed: Value = serde_json::from_str(&result)?;
+    let code = parsed["code"].as_str().unwrap_or_default();
+    // The GeoAPI answers 404 in the body when nothing matched
+    if code == "404" {
+        return Err(WmError::LocationLookup(format!("no city named {}", city)));
+    }
+    if code != "200" {
+        return Err(WmError::LocationLookup(format!("GeoAPI code {}", code)));
+    }
+    let results = parsed["location"].as_array().cloned().unwrap_or_default();
+    let exact: Vec<&Value> = results
+        .iter()
+        .filter(|entry| {
+            json_str!(entry, "name")
+                .trim()
+                .eq_ignore_ascii_case(city.trim())
+        })
+        .collect();
+    let entry = match (exact.as_slice(), results.as_slice()) {
+        ([entry], _) => *entry,
+        ([], [entry]) => entry,
+        ([], []) => return Err(WmError::LocationLookup(format!("no city named {}", city))),
+        _ => {
+            let candidates: Vec<String> = results
+                .iter()
+                .take(GEO_MAX_CANDIDATES)
+                .map(|entry| {
+                    format!(
+                        "{} ({}, {}): {}",
+                        json_str!(entry, "name"),
+                        json_str!(entry, "adm2"),
+                        json_str!(entry, "adm1"),
+                        json_str!(entry, "id")
+                    )
+                })
+                .collect();
+            return Err(WmError::LocationLookup(format!(
+                "{} is ambiguous, set location to one of {}",
+                city,
+                candidates.join("; ")
+            )));
+        }
+    };
+    let id = json_str!(entry, "id");
+    if id.is_empty() {
+        return Err(WmError::LocationLookup(format!("no id for {}", city)));
+    }
+    Ok(id)
+}
+
+pub struct QWeatherProvider {
+    param: String,
+}
+
+impl QWeatherProvider {
+    pub fn new(location: &str, key: &str, lang: Language) -> Self {
+        let param = format!("location={}&key={}&lang={}", location, key, lang.api_code());
+        QWeatherProvider { param }
+    }
+
+    /// Requests go through `client` rather than a fresh `HttpClient`, so
+    /// canned responses can stand in when checking the field mapping.
+    fn current_from(&self, client: &mut dyn HttpGet) -> Result<CurrentWeather> {
+        let url = format!("https://devapi.qweather.com/v7/weather/now?{}", self.param);
+        let weather = get_json_map(client, &url, "now")?;
+
+        let url = format!("https://devapi.qweather.com/v7/air/now?{}", self.param);
+        let aqi = get_json_map(client, &url, "now")?;
+
+        Ok(CurrentWeather {
+            text: json_str!(weather, "text"),
+            temperature: json_i32!(weather, "temp"),
+            feels_like: json_i32!(weather, "feelsLike"),
+            humidity: json_i32!(weather, "humidity"),
+            pressure: json_i32!(weather, "pressure"),
+            precipitation: json_f32!(weather, "precip"),
+            wind_dir: json_str!(weather, "windDir"),
+            wind_360: weather.get("wind360").and_then(value_i32),
+            wind_scale: json_i32!(weather, "windScale"),
+            wind_speed: json_i32!(weather, "windSpeed"),
+            aqi: json_i32!(aqi, "aqi"),
+            aqi_category: json_str!(aqi, "category"),
+            aqi_primary: json_str!(aqi, "primary"),
+            aqi_pm10: json_i32!(aqi, "pm10"),
+            aqi_pm2p5: json_i32!(aqi, "pm2p5"),
+            aqi_so2: json_i32!(aqi, "so2"),
+            aqi_no2: json_i32!(aqi, "no2"),
+            aqi_co: json_f32!(aqi, "co"),
+            aqi_o3: json_i32!(aqi, "o3"),
+            // Not every plan includes uvIndex in weather/now, it stays 0 then
+            uv_index: json_i32!(weather, "uvIndex"),
+            visibility: json_i32!(weather, "vis"),
+            icon: json_i32!(weather, "icon"),
+        })
+    }
+
+    fn daily_from(&self, client: &mut dyn HttpGet) -> Result<Vec<DailyWeather>> {
+        // A week for the range chart, the stacked items only show three days
+        let url = format!("https://devapi.qweather.com/v7/weather/7d?{}", self.param);
+        let weather = get_json_vector(client, &url, "daily")?;
+        let mut daily = Vec::new();
+        for entry in weather.iter() {
+            if let Some(entry) = entry.as_object() {
+                let result = DailyWeather {
+                    date: json_str!(entry, "fxDate"),
+                    text: json_str!(entry, "textDay"),
+                    temp_min: json_i32!(entry, "tempMin"),
+                    temp_max: json_i32!(entry, "tempMax"),
+                    humidity: json_i32!(entry, "humidity"),
+                    wind_dir: json_str!(entry, "windDirDay"),
+                    wind_scale: json_str!(entry, "windScaleDay"),
+                    precipitation: json_f32!(entry, "precip"),
+                    precip_prob: entry.get("pop").and_then(value_i32),
+                    icon: json_i32!(entry, "iconDay"),
+                    sunrise: json_str!(entry, "sunrise"),
+                    sunset: json_str!(entry, "sunset"),
+                };
+                daily.push(result);
+            }
+        }
+        Ok(daily)
+    }
+
+    fn hourly_from(&self, client: &mut dyn HttpGet) -> Result<Vec<HourlyWeather>> {
+        let url = format!("https://devapi.qweather.com/v7/weather/24h?{}", self.param);
+        let weather = get_json_vector(client, &url, "hourly")?;
+        let mut hourly = Vec::new();
+        for entry in weather.iter() {
+            if let Some(entry) = entry.as_object() {
+                let result = HourlyWeather {
+                    time: json_str!(entry, "fxTime"),
+                    text: json_str!(entry, "text"),
+                    temperature: json_i32!(entry, "temp"),
+                    humidity: json_i32!(entry, "humidity"),
+                    pressure: json_i32!(entry, "pressure"),
+                    precipitation: json_f32!(entry, "precip"),
+                    wind_dir: json_str!(entry, "windDir"),
+                    wind_scale: json_str!(entry, "windScale"),
+                    wind_speed: json_i32!(entry, "windSpeed"),
+                    icon: json_i32!(entry, "icon"),
+                };
+                hourly.push(result);
+            }
+        }
+        Ok(hourly)
+    }
+
+    fn alerts_from(&self, client: &mut dyn HttpGet) -> Result<Vec<WeatherAlert>> {
+        let url = format!("https://devapi.qweather.com/v7/warning/now?{}", self.param);
+        let warnings = get_json_vector(client, &url, "warning")?;
+        let alerts = warnings
+            .iter()
+            .filter_map(|entry| entry.as_object())
+            .map(|entry| WeatherAlert {
+                title: json_str!(entry, "title"),
+                level: json_str!(entry, "level"),
+                text: json_str!(entry, "text"),
+            })
+            .collect();
+        Ok(alerts)
+    }
+}
+
+#[cfg(feature = "esp")]
+impl WeatherProvider for QWeatherProvider {
+    fn current(&mut self) -> Result<CurrentWeather> {
+        self.current_from(&mut HttpClient::new()?)
+    }
+
+    fn daily(&mut self) -> Result<Vec<DailyWeather>> {
+        self.daily_from(&mut HttpClient::new()?)
+    }
+
+    fn hourly(&mut self) -> Result<Vec<HourlyWeather>> {
+        self.hourly_from(&mut HttpClient::new()?)
+    }
+
+    fn alerts(&mut self) -> Result<Vec<WeatherAlert>> {
+        self.alerts_from(&mut HttpClient::new()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves canned bodies by the path the URL contains, and a 404 for
+    /// anything else.
+    struct MockHttpClient {
+        responses: Vec<(&'static str, &'static str)>,
+    }
+
+    impl HttpGet for MockHttpClient {
+        fn get(&mut self, url: &str) -> Result<String> {
+            self.responses
+                .iter()
+                .find(|(path, _)| url.contains(path))
+                .map(|(_, body)| String::from(*body))
+                .ok_or(WmError::HttpStatus(404))
+        }
+    }
+
+    const WEATHER_NOW: &str = r#"{"code":"200","now":{"obsTime":"2023-05-20T10:42+08:00",
+        "temp":"24","feelsLike":"23","icon":"101","text":"多云","wind360":"135",
+        "windDir":"东南风","windScale":"2","windSpeed":"8","humidity":"44","precip":"0.3",
+        "pressure":"1008","vis":"30","cloud":"91","dew":"11"}}"#;
+    // Without a dominant pollutant QWeather reports the primary one as "NA"
+    const AIR_NOW: &str = r#"{"code":"200","now":{"pubTime":"2023-05-20T10:00+08:00",
+        "aqi":"46","level":"1","category":"优","primary":"NA","pm10":"46","pm2p5":"13",
+        "no2":"20","so2":"2","co":"0.4","o3":"86"}}"#;
+
+    #[test]
+    fn values_from_strings_and_numbers() {
+        use serde_json::json;
+
+        assert_eq!(value_i32(&json!("24")), Some(24));
+        assert_eq!(value_i32(&json!(" -3 ")), Some(-3));
+        assert_eq!(value_i32(&json!("n/a")), None);
+        assert_eq!(value_i32(&json!(1008)), Some(1008));
+        assert_eq!(value_i32(&json!(23.6)), Some(24));
+        assert_eq!(value_i32(&json!(-2.5)), Some(-3));
+        assert_eq!(value_i32(&json!(null)), None);
+
+        assert_eq!(value_f32(&json!("0.4")), Some(0.4));
+        assert_eq!(value_f32(&json!(2.5)), Some(2.5));
+        assert_eq!(value_f32(&json!(3)), Some(3.0));
+        assert_eq!(value_f32(&json!("")), None);
+    }
+
+    fn provider() -> QWeatherProvider {
+        QWeatherProvider::new("101010100", "key", Language::Chinese)
+    }
+
+    #[test]
+    fn current_maps_fields() {
+        let mut client = MockHttpClient {
+            responses: vec![("/weather/now", WEATHER_NOW), ("/air/now", AIR_NOW)],
+        };
+        let now = provider().current_from(&mut client).unwrap();
+        assert_eq!(now.text, "多云");
+        assert_eq!(now.temperature, 24);
+        assert_eq!(now.feels_like, 23);
+        assert_eq!(now.humidity, 44);
+        assert_eq!(now.pressure, 1008);
+        assert_eq!(now.precipitation, 0.3);
+        assert_eq!(now.wind_dir, "东南风");
+        assert_eq!(now.wind_360, Some(135));
+        assert_eq!(now.wind_scale, 2);
+        assert_eq!(now.wind_speed, 8);
+        assert_eq!(now.visibility, 30);
+        assert_eq!(now.icon, 101);
+        // Missing from this plan's response, so left at zero
+        assert_eq!(now.uv_index, 0);
+
+        assert_eq!(now.aqi, 46);
+        assert_eq!(now.aqi_category, "优");
+        assert_eq!(now.aqi_primary, "NA");
+        assert_eq!(now.aqi_pm10, 46);
+        assert_eq!(now.aqi_pm2p5, 13);
+        assert_eq!(now.aqi_so2, 2);
+        assert_eq!(now.aqi_no2, 20);
+        assert_eq!(now.aqi_co, 0.4);
+        assert_eq!(now.aqi_o3, 86);
+        assert!(now.has_gases());
+    }
+
+    #[test]
+    fn current_fails_without_air_quality() {
+        let mut client = MockHttpClient {
+            responses: vec![("/weather/now", WEATHER_NOW)],
+        };
+        assert!(matches!(
+            provider().current_from(&mut client),
+            Err(WmError::HttpStatus(404))
+        ));
+
+        let mut client = MockHttpClient {
+            responses: vec![
+                ("/weather/now", WEATHER_NOW),
+                ("/air/now", r#"{"code":"204"}"#),
+            ],
+        };
+        assert!(matches!(
+            provider().current_from(&mut client),
+            Err(WmError::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn daily_maps_fields() {
+        let body = r#"{"code":"200","daily":[
+            {"fxDate":"2023-05-20","sunrise":"04:57","sunset":"19:28","tempMax":"30",
+             "tempMin":"17","iconDay":"100","textDay":"晴","windDirDay":"南风",
+             "windScaleDay":"1-3","humidity":"40","precip":"0.0","pop":"10"},
+            {"fxDate":"2023-05-21","tempMax":"28","tempMin":"18","iconDay":"305",
+             "textDay":"小雨","precip":"2.5"}]}"#;
+        let mut client = MockHttpClient {
+            responses: vec![("/weather/7d", body)],
+        };
+        let daily = provider().daily_from(&mut client).unwrap();
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily[0].date, "2023-05-20");
+        assert_eq!(daily[0].text, "晴");
+        assert_eq!((daily[0].temp_min, daily[0].temp_max), (17, 30));
+        assert_eq!(daily[0].wind_scale, "1-3");
+        assert_eq!(daily[0].precip_prob, Some(10));
+        assert_eq!(daily[0].sunrise, "04:57");
+        assert_eq!(daily[1].icon, 305);
+        assert_eq!(daily[1].precipitation, 2.5);
+        assert_eq!(daily[1].precip_prob, None);
+    }
+
+    #[test]
+    fn hourly_maps_fields() {
+        let body = r#"{"code":"200","hourly":[
+            {"fxTime":"2023-05-20T11:00+08:00","temp":"25","icon":"101","text":"多云",
+             "windDir":"南风","windScale":"1-3","windSpeed":"10","humidity":"41",
+             "precip":"0.0","pressure":"1007"}]}"#;
+        let mut client = MockHttpClient {
+            responses: vec![("/weather/24h", body)],
+        };
+        let hourly = provider().hourly_from(&mut client).unwrap();
+        assert_eq!(hourly.len(), 1);
+        assert_eq!(hourly[0].time, "2023-05-20T11:00+08:00");
+        assert_eq!(hourly[0].temperature, 25);
+        assert_eq!(hourly[0].wind_speed, 10);
+        assert_eq!(hourly[0].pressure, 1007);
+    }
+}
@@ -0,0 +1,105 @@
+use time::Weekday;
+
+/// Language of the labels drawn on the panel and of the weather texts
+/// requested from the provider.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Chinese,
+    English,
+}
+
+impl Language {
+    /// Parses the `language` config value, falling back to Chinese.
+    pub fn from_code(code: &str) -> Self {
+        match code.trim().to_ascii_lowercase().as_str() {
+            "en" | "english" => Language::English,
+            _ => Language::Chinese,
+        }
+    }
+
+    /// Value of the QWeather `lang` parameter.
+    pub fn api_code(&self) -> &'static str {
+        match self {
+            Language::Chinese => "cn",
+            Language::English => "en",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Label {
+    WindLevel,
+    AirQuality,
+    Outdoor,
+    Indoor,
+    Precipitation,
+    FeelsLike,
+    Pressure,
+    Sunrise,
+    Sunset,
+    IndoorHistory,
+    Updated,
+    Comfortable,
+    Dry,
+    Humid,
+    LowBattery,
+}
+
+/// Units are left out of the labels, callers append the configured ones.
+pub fn label(lang: Language, label: Label) -> &'static str {
+    match (lang, label) {
+        (Language::Chinese, Label::WindLevel) => "级",
+        (Language::Chinese, Label::AirQuality) => "空气质量",
+        (Language::Chinese, Label::Outdoor) => "室外",
+        (Language::Chinese, Label::Indoor) => "室内",
+        (Language::Chinese, Label::Precipitation) => "降水 mm",
+        (Language::Chinese, Label::FeelsLike) => "体感",
+        (Language::Chinese, Label::Pressure) => "气压",
+        (Language::Chinese, Label::Sunrise) => "日出",
+        (Language::Chinese, Label::Sunset) => "日落",
+        (Language::Chinese, Label::IndoorHistory) => "室内温湿度",
+        (Language::Chinese, Label::Updated) => "更新",
+        (Language::Chinese, Label::Comfortable) => "舒适",
+        (Language::Chinese, Label::Dry) => "干燥",
+        (Language::Chinese, Label::Humid) => "潮湿",
+        (Language::Chinese, Label::LowBattery) => "电量低，请充电",
+        (Language::English, Label::WindLevel) => "Bft",
+        (Language::English, Label::AirQuality) => "AQI",
+        (Language::English, Label::Outdoor) => "Out",
+        (Language::English, Label::Indoor) => "In",
+        (Language::English, Label::Precipitation) => "Rain mm",
+        (Language::English, Label::FeelsLike) => "Feel",
+        (Language::English, Label::Pressure) => "",
+        (Language::English, Label::Sunrise) => "Rise",
+        (Language::English, Label::Sunset) => "Set",
+        (Language::English, Label::IndoorHistory) => "Indoor",
+        (Language::English, Label::Updated) => "Upd",
+        (Language::English, Label::Comfortable) => "Comfy",
+        (Language::English, Label::Dry) => "Dry",
+        (Language::English, Label::Humid) => "Humid",
+        (Language::English, Label::LowBattery) => "Low battery, please charge",
+    }
+}
+
+pub fn weekday(lang: Language, weekday: Weekday) -> &'static str {
+    match lang {
+        Language::Chinese => match weekday {
+            Weekday::Monday => "星期一",
+            Weekday::Tuesday => "星期二",
+            Weekday::Wednesday => "星期三",
+            Weekday::Thursday => "星期四",
+            Weekday::Friday => "星期五",
+            Weekday::Saturday => "星期六",
+            Weekday::Sunday => "星期日",
+        },
+        Language::English => match weekday {
+            Weekday::Monday => "Mon",
+            Weekday::Tuesday => "Tue",
+            Weekday::Wednesday => "Wed",
+            Weekday::Thursday => "Thu",
+            Weekday::Friday => "Fri",
+            Weekday::Saturday => "Sat",
+            Weekday::Sunday => "Sun",
+        },
+    }
+}
@@ -0,0 +1,230 @@
+use crate::display::{Color, Display};
+use crate::error::Result;
+
+use embedded_graphics::prelude::*;
+use u8g2_fonts::{fonts, types::*, FontRenderer};
+
+pub fn text_width(font: &FontRenderer, text: &str) -> Result<i32> {
+    let dimensions = font.get_rendered_dimensions(text, Point::zero(), VerticalPosition::Top)?;
+    Ok(dimensions.advance.x)
+}
+
+/// Splits one line of text into words, each paired with whether a space
+/// preceded it. Non-ASCII characters such as CJK become words of their own.
+fn split_words(line: &str) -> Vec<(bool, String)> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut space = false;
+    for ch in line.chars() {
+        if ch == ' ' {
+            if !word.is_empty() {
+                words.push((space, std::mem::take(&mut word)));
+            }
+            space = true;
+        } else if ch.is_ascii() {
+            word.push(ch);
+        } else {
+            if !word.is_empty() {
+                words.push((space, std::mem::take(&mut word)));
+                space = false;
+            }
+            words.push((space, ch.to_string()));
+            space = false;
+        }
+    }
+    if !word.is_empty() {
+        words.push((space, word));
+    }
+    words
+}
+
+/// Renders the ASCII runs of a note with Courier and everything else with the
+/// CJK font, so a note mixing both keeps each legible.
+pub struct NoteFont {
+    ascii: FontRenderer,
+    cjk: FontRenderer,
+    pub line_height: i32,
+}
+
+impl NoteFont {
+    pub fn new(content: &str) -> Self {
+        let ascii =
+            FontRenderer::new::<fonts::u8g2_font_courR10_tf>().with_ignore_unknown_chars(true);
+        let cjk =
+            FontRenderer::new::<fonts::u8g2_font_wqy16_t_gb2312>().with_ignore_unknown_chars(true);
+        // Lines only grow to fit the CJK font when the note uses it
+        let line_height = if content.is_ascii() {
+            ascii.get_default_line_height()
+        } else {
+            ascii
+                .get_default_line_height()
+                .max(cjk.get_default_line_height())
+        };
+        NoteFont {
+            ascii,
+            cjk,
+            line_height: line_height as i32,
+        }
+    }
+
+    /// Splits `text` into runs of ASCII and non-ASCII characters, each with
+    /// the font that draws it.
+    fn runs<'t>(&self, text: &'t str) -> Vec<(&FontRenderer, &'t str)> {
+        let mut runs = Vec::new();
+        let mut start = 0;
+        let mut chars = text.char_indices().peekable();
+        while let Some((_, ch)) = chars.next() {
+            let next = chars.peek();
+            if next.is_none_or(|(_, next)| next.is_ascii() != ch.is_ascii()) {
+                let end = next.map_or(text.len(), |(idx, _)| *idx);
+                let font = if ch.is_ascii() {
+                    &self.ascii
+                } else {
+                    &self.cjk
+                };
+                runs.push((font, &text[start..end]));
+                start = end;
+            }
+        }
+        runs
+    }
+
+    fn width(&self, text: &str) -> Result<i32> {
+        let mut width = 0;
+        for (font, run) in self.runs(text) {
+            width += text_width(font, run)?;
+        }
+        Ok(width)
+    }
+
+    /// Draws one line with its top at `top_left` and returns its width. The
+    /// runs share the bottom of the line, which keeps their baselines close.
+    pub fn render(
+        &self,
+        display: &mut Display,
+        text: &str,
+        top_left: Point,
+        color: Color,
+    ) -> Result<i32> {
+        let mut x = 0;
+        for (font, run) in self.runs(text) {
+            font.render_aligned(
+                run,
+                top_left + Point::new(x, self.line_height),
+                VerticalPosition::Bottom,
+                HorizontalAlignment::Left,
+                FontColor::Transparent(color),
+                display,
+            )?;
+            x += text_width(font, run)?;
+        }
+        Ok(x)
+    }
+}
+
+/// Breaks `content` into lines no wider than `width` pixels, wrapping at
+/// spaces where possible and keeping explicit newlines.
+pub fn wrap_text(font: &NoteFont, content: &str, width: i32) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for paragraph in content.lines() {
+        let mut line = String::new();
+        for (space, word) in split_words(paragraph) {
+            let mut candidate = line.clone();
+            if space && !line.is_empty() {
+                candidate.push(' ');
+            }
+            candidate.push_str(&word);
+            if line.is_empty() || font.width(&candidate)? <= width {
+                line = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut line, word));
+            }
+
+            // A single word wider than the box is broken per character
+            while line.chars().count() > 1 && font.width(&line)? > width {
+                let mut split = 0;
+                for (idx, ch) in line.char_indices() {
+                    let end = idx + ch.len_utf8();
+                    if split > 0 && font.width(&line[..end])? > width {
+                        break;
+                    }
+                    split = end;
+                }
+                let rest = line.split_off(split);
+                lines.push(std::mem::replace(&mut line, rest));
+            }
+        }
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// Draws `content` wrapped to `width` pixels starting at `top_left` and
+/// returns the cursor position right after the last character.
+pub fn draw_wrapped_text(
+    display: &mut Display,
+    font: &NoteFont,
+    content: &str,
+    top_left: Point,
+    width: u32,
+    color: Color,
+) -> Result<Point> {
+    let mut cursor = top_left;
+    for (idx, line) in wrap_text(font, content, width as i32)?.iter().enumerate() {
+        let position = top_left + Point::new(0, font.line_height * idx as i32);
+        let width = font.render(display, line, position, color)?;
+        cursor = position + Point::new(width, 0);
+    }
+    Ok(cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_keeps_newlines() {
+        let font = NoteFont::new("");
+        let lines = wrap_text(&font, "first\n\nthird line", 256).unwrap();
+        assert_eq!(lines, vec!["first", "", "third line"]);
+    }
+
+    #[test]
+    fn wrap_at_spaces() {
+        let font = NoteFont::new("");
+        let width = font.width("one two").unwrap();
+        let lines = wrap_text(&font, "one two three", width).unwrap();
+        assert_eq!(lines, vec!["one two", "three"]);
+    }
+
+    #[test]
+    fn wrap_splits_long_word() {
+        let font = NoteFont::new("");
+        // Courier is monospaced, so three characters fit on every line
+        let width = font.width("abc").unwrap();
+        let lines = wrap_text(&font, "abcdefgh", width).unwrap();
+        assert_eq!(lines, vec!["abc", "def", "gh"]);
+
+        // Even a box narrower than one character takes a character per line
+        let lines = wrap_text(&font, "abc", 1).unwrap();
+        assert_eq!(lines, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn wrap_mixed_width() {
+        let content = "abc温度";
+        let font = NoteFont::new(content);
+        let runs: Vec<&str> = font.runs("ab温度c").iter().map(|(_, run)| *run).collect();
+        assert_eq!(runs, vec!["ab", "温度", "c"]);
+        assert_eq!(
+            font.width("abc温").unwrap(),
+            text_width(&font.ascii, "abc").unwrap() + text_width(&font.cjk, "温").unwrap()
+        );
+        // Each CJK character may start a new line
+        let width = font.width("abc温").unwrap();
+        let lines = wrap_text(&font, content, width).unwrap();
+        assert_eq!(lines, vec!["abc温", "度"]);
+        // Lines only grow for the CJK font when the note uses it
+        assert!(NoteFont::new("abc").line_height <= font.line_height);
+    }
+}
@@ -0,0 +1,51 @@
+/// Units the panel shows temperatures and pressures in. Weather and sensor
+/// values are always kept metric and only converted when drawn.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    Metric,
+    Imperial,
+}
+
+impl Units {
+    /// Parses the `units` config value, falling back to metric.
+    pub fn from_code(code: &str) -> Self {
+        match code.trim().to_ascii_lowercase().as_str() {
+            "imperial" | "us" => Units::Imperial,
+            _ => Units::Metric,
+        }
+    }
+
+    pub fn temperature(&self, celsius: f32) -> f32 {
+        match self {
+            Units::Metric => celsius,
+            Units::Imperial => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// Converts a whole degree Celsius value, rounding to a whole degree.
+    pub fn degrees(&self, celsius: i32) -> i32 {
+        self.temperature(celsius as f32).round() as i32
+    }
+
+    pub fn temperature_symbol(&self) -> &'static str {
+        match self {
+            Units::Metric => "°C",
+            Units::Imperial => "°F",
+        }
+    }
+
+    /// Formats a pressure in hPa, inHg needs two decimals to be useful.
+    pub fn pressure(&self, hpa: f32) -> String {
+        match self {
+            Units::Metric => format!("{:.0}", hpa),
+            Units::Imperial => format!("{:.2}", hpa * 0.02953),
+        }
+    }
+
+    pub fn pressure_symbol(&self) -> &'static str {
+        match self {
+            Units::Metric => "hPa",
+            Units::Imperial => "inHg",
+        }
+    }
+}
@@ -1,9 +1,23 @@
 use crate::error::{Result, WmError};
-use crate::network::http::HttpClient;
-use serde_json::Map;
-use serde_json::Value;
+#[cfg(feature = "esp")]
+use crate::log;
+#[cfg(feature = "esp")]
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
 
-#[derive(Default)]
+const NVS_NAMESPACE: &str = "weather";
+const NVS_KEY: &str = "last_good";
+// Bump whenever CurrentWeather or DailyWeather changes so stale blobs are dropped
+const NVS_VERSION: u8 = 5;
+const NVS_MAX_SIZE: usize = 4096;
+
+const BACKOFF_BASE: Duration = Duration::from_secs(60);
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+const STALE_AFTER: Duration = Duration::from_secs(2 * 60 * 60);
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct CurrentWeather {
     pub text: String,
     pub temperature: i32,
@@ -12,6 +26,8 @@ pub struct CurrentWeather {
     pub pressure: i32,
     pub precipitation: f32,
     pub wind_dir: String,
+    // Wind direction in degrees, if the provider reports it
+    pub wind_360: Option<i32>,
     pub wind_scale: i32,
     pub wind_speed: i32,
     pub aqi: i32,
@@ -19,9 +35,23 @@ pub struct CurrentWeather {
     pub aqi_primary: String,
     pub aqi_pm10: i32,
     pub aqi_pm2p5: i32,
+    // Gases in μg/m³, except CO in mg/m³
+    pub aqi_so2: i32,
+    pub aqi_no2: i32,
+    pub aqi_co: f32,
+    pub aqi_o3: i32,
+    pub uv_index: i32,
+    pub visibility: i32,
     pub icon: i32,
 }
 
+impl CurrentWeather {
+    /// False when the air quality response left out all gas readings.
+    pub fn has_gases(&self) -> bool {
+        self.aqi_so2 != 0 || self.aqi_no2 != 0 || self.aqi_co != 0.0 || self.aqi_o3 != 0
+    }
+}
+
 #[derive(Default)]
 pub struct HourlyWeather {
     pub time: String,
@@ -36,7 +66,7 @@ pub struct HourlyWeather {
     pub icon: i32,
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct DailyWeather {
     pub date: String,
     pub text: String,
@@ -44,6 +74,8 @@ pub struct DailyWeather {
     pub temp_max: i32,
     pub humidity: i32,
     pub precipitation: f32,
+    // Probability of precipitation in percent, if the provider reports it
+    pub precip_prob: Option<i32>,
     pub wind_dir: String,
     pub wind_scale: String,
     pub icon: i32,
@@ -51,185 +83,243 @@ pub struct DailyWeather {
     pub sunset: String,
 }
 
+impl DailyWeather {
+    /// The `MM-DD` part of a `YYYY-MM-DD` date, or the date as given when it
+    /// is shorter or not in that format.
+    pub fn month_day(&self) -> &str {
+        self.date.get(5..10).unwrap_or(&self.date)
+    }
+}
+
+/// An active severe weather warning.
+#[derive(Default)]
+pub struct WeatherAlert {
+    pub title: String,
+    // Colour coded level as named by the provider, e.g. "Blue" or "Red"
+    pub level: String,
+    pub text: String,
+}
+
+#[cfg(feature = "esp")]
 pub struct WeatherInfo {
     pub now: CurrentWeather,
     pub hourly: Vec<HourlyWeather>,
     pub daily: Vec<DailyWeather>,
+    // Empty while no warning is in effect
+    pub alerts: Vec<WeatherAlert>,
     pub valid: bool,
-    param: String,
+    storage: Option<EspDefaultNvs>,
+    failures: u32,
+    last_update: Option<Instant>,
+    next_attempt: Option<Instant>,
 }
 
-impl Default for WeatherInfo {
-    fn default() -> Self {
-        let now = CurrentWeather {
-            ..Default::default()
-        };
-        WeatherInfo {
-            now,
+/// Source of weather data. Implementations translate the service specific
+/// responses into the structures used by the drawing code. Providers are
+/// moved to the fetch thread, hence `Send`.
+pub trait WeatherProvider: Send {
+    fn current(&mut self) -> Result<CurrentWeather>;
+    fn daily(&mut self) -> Result<Vec<DailyWeather>>;
+    fn hourly(&mut self) -> Result<Vec<HourlyWeather>>;
+    fn alerts(&mut self) -> Result<Vec<WeatherAlert>>;
+}
+
+#[cfg(feature = "esp")]
+impl WeatherInfo {
+    pub fn new(nvs: Option<EspDefaultNvsPartition>) -> Self {
+        let storage = nvs.and_then(|nvs| EspDefaultNvs::new(nvs, NVS_NAMESPACE, true).ok());
+        let mut info = WeatherInfo {
+            now: CurrentWeather::default(),
             hourly: Vec::new(),
             daily: Vec::new(),
+            alerts: Vec::new(),
             valid: false,
-            param: "".into(),
+            storage,
+            failures: 0,
+            last_update: None,
+            next_attempt: None,
+        };
+        if let Err(err) = info.load() {
+            println!("No saved weather loaded: {}", err);
         }
+        info
     }
-}
 
-fn get_json_map(url: &str, key: &str) -> Result<Map<String, Value>> {
-    let mut client = HttpClient::new()?;
-    let result = client.get(url)?;
-    let parsed: Value = serde_json::from_str(&result)?;
-    let now = parsed[key].as_object();
-    if let Some(now) = now {
-        Ok(now.clone())
-    } else {
-        Err(WmError::InvalidArgument)
+    fn load(&mut self) -> Result<()> {
+        let storage = self.storage.as_ref().ok_or(WmError::InvalidArgument)?;
+        let mut buf = Vec::new();
+        buf.resize(NVS_MAX_SIZE, 0);
+        let blob = storage
+            .get_raw(NVS_KEY, &mut buf)?
+            .ok_or(WmError::InvalidArgument)?;
+        if blob.first() != Some(&NVS_VERSION) {
+            return Err(WmError::InvalidArgument);
+        }
+        let (now, daily): (CurrentWeather, Vec<DailyWeather>) = serde_json::from_slice(&blob[1..])?;
+        self.now = now;
+        self.daily = daily;
+        self.valid = true;
+        Ok(())
     }
-}
 
-fn get_json_vector(url: &str, key: &str) -> Result<Vec<Value>> {
-    let mut client = HttpClient::new()?;
-    let result = client.get(url)?;
-    let parsed: Value = serde_json::from_str(&result)?;
-    let now = parsed[key].as_array();
-    if let Some(now) = now {
-        Ok(now.clone())
-    } else {
-        Err(WmError::InvalidArgument)
+    fn save(&mut self) -> Result<()> {
+        let mut blob = vec![NVS_VERSION];
+        blob.extend(serde_json::to_vec(&(&self.now, &self.daily))?);
+        if blob.len() > NVS_MAX_SIZE {
+            return Err(WmError::InvalidArgument);
+        }
+        if let Some(storage) = self.storage.as_mut() {
+            storage.set_raw(NVS_KEY, &blob)?;
+        }
+        Ok(())
     }
-}
 
-macro_rules! json_str {
-    ($entry:expr, $item:literal) => {{
-        let v = $entry.get($item);
-        if let Some(v) = v {
-            String::from(v.as_str().unwrap_or_default())
-        } else {
-            String::from("")
+    /// Drops the copy saved in NVS, so a reboot after a factory reset doesn't
+    /// bring back weather fetched for the old location.
+    pub fn forget_saved(&mut self) -> Result<()> {
+        if let Some(storage) = self.storage.as_mut() {
+            storage.remove(NVS_KEY)?;
         }
-    }};
-}
+        Ok(())
+    }
 
-macro_rules! json_i32 {
-    ($entry:expr, $item:literal) => {{
-        let v = $entry.get($item);
-        if let Some(v) = v {
-            v.as_str()
-                .unwrap_or_default()
-                .parse::<i32>()
-                .unwrap_or_default()
-        } else {
-            0
-        }
-    }};
-}
+    /// Clears any pending backoff so the next update fetches immediately,
+    /// e.g. after the location changed.
+    pub fn reset_backoff(&mut self) {
+        self.failures = 0;
+        self.next_attempt = None;
+    }
 
-macro_rules! json_f32 {
-    ($entry:expr, $item:literal) => {{
-        let v = $entry.get($item);
-        if let Some(v) = v {
-            v.as_str()
-                .unwrap_or_default()
-                .parse::<f32>()
-                .unwrap_or_default()
-        } else {
-            0.0
-        }
-    }};
-}
+    /// Seconds since the last successful update, if any happened since boot.
+    pub fn age(&self) -> Option<u64> {
+        self.last_update
+            .map(|last_update| last_update.elapsed().as_secs())
+    }
 
-impl WeatherInfo {
-    pub fn new(location: &str, key: &str) -> Self {
-        let param = format!("location={}&key={}&lang=cn", location, key);
-        WeatherInfo {
-            param,
-            ..Default::default()
-        }
+    /// Wall clock time of the last successful update. Only an `Instant` is
+    /// kept, so the time is derived from the current time `now`.
+    pub fn last_update(&self, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        self.age()
+            .map(|age| now - time::Duration::seconds(age as i64))
     }
 
-    fn try_update_current_weather(&mut self) {
-        let url = format!("https://devapi.qweather.com/v7/weather/now?{}", self.param);
-        let weather = get_json_map(&url, "now");
-
-        let url = format!("https://devapi.qweather.com/v7/air/now?{}", self.param);
-        let aqi = get_json_map(&url, "now");
-
-        if let Ok(weather) = weather {
-            if let Ok(aqi) = aqi {
-                self.now = CurrentWeather {
-                    text: json_str!(weather, "text"),
-                    temperature: json_i32!(weather, "temp"),
-                    feels_like: json_i32!(weather, "feelsLike"),
-                    humidity: json_i32!(weather, "humidity"),
-                    pressure: json_i32!(weather, "pressure"),
-                    precipitation: json_f32!(weather, "precip"),
-                    wind_dir: json_str!(weather, "windDir"),
-                    wind_scale: json_i32!(weather, "windScale"),
-                    wind_speed: json_i32!(weather, "windSpeed"),
-                    aqi: json_i32!(aqi, "aqi"),
-                    aqi_category: json_str!(aqi, "category"),
-                    aqi_primary: json_str!(aqi, "primary"),
-                    aqi_pm10: json_i32!(aqi, "pm10"),
-                    aqi_pm2p5: json_i32!(aqi, "pm2p5"),
-                    icon: json_i32!(weather, "icon"),
-                };
-                self.valid = true;
-            }
+    /// Returns true if the data shown was not refreshed successfully during
+    /// the last two hours, including data restored from NVS after a reboot.
+    pub fn is_stale(&self) -> bool {
+        match self.last_update {
+            Some(last_update) => last_update.elapsed() > STALE_AFTER,
+            None => true,
         }
     }
 
-    fn try_update_daily_weather(&mut self) {
-        let url = format!("https://devapi.qweather.com/v7/weather/3d?{}", self.param);
-        let weather = get_json_vector(&url, "daily");
-        if let Ok(weather) = weather {
-            self.daily.clear();
-            for entry in weather.iter() {
-                if let Some(entry) = entry.as_object() {
-                    let result = DailyWeather {
-                        date: json_str!(entry, "fxDate"),
-                        text: json_str!(entry, "textDay"),
-                        temp_min: json_i32!(entry, "tempMin"),
-                        temp_max: json_i32!(entry, "tempMax"),
-                        humidity: json_i32!(entry, "humidity"),
-                        wind_dir: json_str!(entry, "windDirDay"),
-                        wind_scale: json_str!(entry, "windScaleDay"),
-                        precipitation: json_f32!(entry, "precip"),
-                        icon: json_i32!(entry, "iconDay"),
-                        sunrise: json_str!(entry, "sunrise"),
-                        sunset: json_str!(entry, "sunset"),
-                    };
-                    self.daily.push(result);
+    /// False while backing off after failed updates.
+    pub fn is_due(&self) -> bool {
+        self.next_attempt
+            .map_or(true, |next_attempt| Instant::now() >= next_attempt)
+    }
+
+    /// Takes over whatever `update` managed to fetch. The update counts as
+    /// successful if either the current or the daily weather came through.
+    pub fn apply(&mut self, update: WeatherUpdate) {
+        let current = update.now.is_some();
+        let daily = update.daily.is_some();
+        if let Some(now) = update.now {
+            self.now = now;
+            self.valid = true;
+        }
+        if let Some(daily) = update.daily {
+            self.daily = daily;
+        }
+        if let Some(hourly) = update.hourly {
+            self.hourly = hourly;
+        }
+        if let Some(alerts) = update.alerts {
+            for alert in alerts.iter() {
+                if !self.alerts.iter().any(|known| known.title == alert.title) {
+                    log::push(format!("Weather alert ({}): {}", alert.level, alert.text));
                 }
             }
+            self.alerts = alerts;
+        }
+        if current || daily {
+            self.failures = 0;
+            self.last_update = Some(Instant::now());
+            self.next_attempt = None;
+            log::push("Weather updated");
+            if let Err(err) = self.save() {
+                println!("Failed to save weather: {}", err);
+            }
+        } else {
+            // Back off 1, 2, 4, ... minutes, capped at BACKOFF_MAX
+            let delay = BACKOFF_BASE
+                .saturating_mul(1 << self.failures.min(5))
+                .min(BACKOFF_MAX);
+            self.failures += 1;
+            self.next_attempt = Some(Instant::now() + delay);
+            log::push(format!(
+                "Weather update failed {} time(s), next attempt in {}s",
+                self.failures,
+                delay.as_secs()
+            ));
         }
     }
+}
 
-    fn _try_update_hourly_weather(&mut self) {
-        let url = format!("https://devapi.qweather.com/v7/weather/24h?{}", self.param);
-        let weather = get_json_vector(&url, "hourly");
-        if let Ok(weather) = weather {
-            self.hourly.clear();
-            for entry in weather.iter() {
-                if let Some(entry) = entry.as_object() {
-                    let result = HourlyWeather {
-                        time: json_str!(entry, "fxTime"),
-                        text: json_str!(entry, "text"),
-                        temperature: json_i32!(entry, "temp"),
-                        humidity: json_i32!(entry, "humidity"),
-                        pressure: json_i32!(entry, "pressure"),
-                        precipitation: json_f32!(entry, "precip"),
-                        wind_dir: json_str!(entry, "windDir"),
-                        wind_scale: json_str!(entry, "windScale"),
-                        wind_speed: json_i32!(entry, "windSpeed"),
-                        icon: json_i32!(entry, "icon"),
-                    };
-                    self.hourly.push(result);
-                }
+/// The results of one round of requests to a provider, made without holding
+/// on to the `WeatherInfo` they will be applied to.
+#[cfg(feature = "esp")]
+pub struct WeatherUpdate {
+    now: Option<CurrentWeather>,
+    daily: Option<Vec<DailyWeather>>,
+    hourly: Option<Vec<HourlyWeather>>,
+    alerts: Option<Vec<WeatherAlert>>,
+}
+
+#[cfg(feature = "esp")]
+impl WeatherUpdate {
+    pub fn fetch(provider: &mut dyn WeatherProvider) -> Self {
+        let now = match provider.current() {
+            Ok(now) => Some(now),
+            Err(err) => {
+                log::push(format!("Failed to update current weather: {}", err));
+                None
+            }
+        };
+        let daily = match provider.daily() {
+            Ok(daily) => Some(daily),
+            Err(err) => {
+                log::push(format!("Failed to update daily weather: {}", err));
+                None
             }
+        };
+        WeatherUpdate {
+            now,
+            daily,
+            hourly: provider.hourly().ok(),
+            // Kept as before when the request fails, so a warning doesn't
+            // vanish just because one update failed
+            alerts: provider.alerts().ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daily(date: &str) -> DailyWeather {
+        DailyWeather {
+            date: date.to_string(),
+            ..Default::default()
         }
     }
 
-    pub fn try_update(&mut self) {
-        self.try_update_current_weather();
-        self.try_update_daily_weather();
+    #[test]
+    fn month_day_of_dates() {
+        assert_eq!(daily("2023-06-01").month_day(), "06-01");
+        assert_eq!(daily("").month_day(), "");
+        assert_eq!(daily("2023-6").month_day(), "2023-6");
+        // Not on a character boundary
+        assert_eq!(daily("2023年06月01日").month_day(), "2023年06月01日");
     }
 }
@@ -0,0 +1,76 @@
+use crate::display::{Color, Display};
+use crate::error::Result;
+
+// 16-point compass, clockwise from north in 22.5 degree steps
+const COMPASS_POINTS: [&str; 16] = [
+    "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW", "NW",
+    "NNW",
+];
+
+// The Chinese names QWeather uses for the eight main directions
+const CHINESE_POINTS: [&str; 8] = [
+    "北风",
+    "东北风",
+    "东风",
+    "东南风",
+    "南风",
+    "西南风",
+    "西风",
+    "西北风",
+];
+
+/// Returns the bearing the wind blows from in degrees clockwise from north.
+/// The numeric `wind_360` is preferred, otherwise `wind_dir` is looked up as
+/// a compass point. None for calm or variable wind and unknown names.
+pub fn wind_bearing(wind_360: Option<i32>, wind_dir: &str) -> Option<f32> {
+    if let Some(degrees) = wind_360 {
+        return Some(degrees.rem_euclid(360) as f32);
+    }
+    let wind_dir = wind_dir.trim();
+    if let Some(index) = COMPASS_POINTS
+        .iter()
+        .position(|point| point.eq_ignore_ascii_case(wind_dir))
+    {
+        return Some(index as f32 * 22.5);
+    }
+    CHINESE_POINTS
+        .iter()
+        .position(|point| *point == wind_dir)
+        .map(|index| index as f32 * 45.0)
+}
+
+/// Draws an arrow centered on `(x, y)` that points where the wind blows to,
+/// i.e. opposite to `bearing`, so a north wind points down.
+pub fn draw_wind_arrow(
+    display: &mut Display,
+    x: i32,
+    y: i32,
+    radius: i32,
+    bearing: f32,
+    color: Color,
+) -> Result<()> {
+    let heading = (bearing + 180.0).to_radians();
+    // Screen y grows downwards, so north is -y
+    let (dx, dy) = (heading.sin(), -heading.cos());
+    let offset = |from: (i32, i32), (ux, uy): (f32, f32), length: f32| {
+        (
+            (from.0 as f32 + ux * length).round() as i32,
+            (from.1 as f32 + uy * length).round() as i32,
+        )
+    };
+    let radius = radius as f32;
+    let tip = offset((x, y), (dx, dy), radius);
+    let tail = offset((x, y), (dx, dy), -radius);
+    display.line(tail.0, tail.1, tip.0, tip.1, color)?;
+    // The barbs start at the tip and sweep back at 30 degrees to the shaft
+    for angle in [-150f32, 150.0] {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let barb = offset(
+            tip,
+            (dx * cos - dy * sin, dx * sin + dy * cos),
+            radius * 0.6,
+        );
+        display.line(tip.0, tip.1, barb.0, barb.1, color)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+// Magnus formula coefficients (Alduchov & Eskridge, 1996)
+const MAGNUS_B: f32 = 17.625;
+const MAGNUS_C: f32 = 243.04;
+
+// Indoor comfort bands: below DRY_HUMIDITY the air feels dry, above
+// HUMID_HUMIDITY or HUMID_DEW_POINT (°C) it feels muggy
+const DRY_HUMIDITY: f32 = 30.0;
+const HUMID_HUMIDITY: f32 = 70.0;
+const HUMID_DEW_POINT: f32 = 16.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ComfortLevel {
+    Comfortable,
+    Dry,
+    Humid,
+}
+
+/// Rates an indoor reading in °C and %. A high dew point counts as humid
+/// even at moderate relative humidity, since warm air holds more water.
+pub fn comfort_level(temp: f32, humidity: f32) -> ComfortLevel {
+    if humidity > HUMID_HUMIDITY || dew_point(temp, humidity) > HUMID_DEW_POINT {
+        ComfortLevel::Humid
+    } else if humidity < DRY_HUMIDITY {
+        ComfortLevel::Dry
+    } else {
+        ComfortLevel::Comfortable
+    }
+}
+
+/// Dew point in °C for a temperature in °C and relative humidity in %.
+pub fn dew_point(temp: f32, humidity: f32) -> f32 {
+    let humidity = humidity.clamp(1.0, 100.0);
+    let gamma = (humidity / 100.0).ln() + MAGNUS_B * temp / (MAGNUS_C + temp);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+/// Heat index in °C for a temperature in °C and relative humidity in %,
+/// following the NWS Rothfusz regression and its adjustments.
+pub fn heat_index(temp: f32, humidity: f32) -> f32 {
+    let t = temp as f64 * 9.0 / 5.0 + 32.0;
+    let rh = humidity as f64;
+
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (simple + t) / 2.0 < 80.0 {
+        return ((simple - 32.0) * 5.0 / 9.0) as f32;
+    }
+
+    let mut hi = -42.379 + 2.04901523 * t + 10.14333127 * rh
+        - 0.22475541 * t * rh
+        - 0.00683783 * t * t
+        - 0.05481717 * rh * rh
+        + 0.00122874 * t * t * rh
+        + 0.00085282 * t * rh * rh
+        - 0.00000199 * t * t * rh * rh;
+    if rh < 13.0 && (80.0..=112.0).contains(&t) {
+        hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+    } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+        hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+    }
+    ((hi - 32.0) * 5.0 / 9.0) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fahrenheit(celsius: f32) -> f32 {
+        celsius * 9.0 / 5.0 + 32.0
+    }
+
+    fn celsius(fahrenheit: f32) -> f32 {
+        (fahrenheit - 32.0) * 5.0 / 9.0
+    }
+
+    #[test]
+    fn dew_point_reference() {
+        // Reference values from the Magnus formula tables
+        assert!((dew_point(25.0, 60.0) - 16.7).abs() < 0.1);
+        assert!((dew_point(20.0, 50.0) - 9.3).abs() < 0.1);
+        assert!((dew_point(0.0, 80.0) - -3.0).abs() < 0.1);
+        // Saturated air is at its dew point
+        assert!((dew_point(12.0, 100.0) - 12.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn heat_index_reference() {
+        // Entries of the NWS heat index chart, which rounds to whole °F
+        for (temp, humidity, expected) in
+            [(90.0, 70.0, 106.0), (96.0, 65.0, 121.0), (84.0, 40.0, 84.0)]
+        {
+            let index = fahrenheit(heat_index(celsius(temp), humidity));
+            assert!(
+                (index - expected).abs() < 1.0,
+                "{}°F at {}%: {}",
+                temp,
+                humidity,
+                index
+            );
+        }
+        // Mild conditions use the simple formula, close to the temperature
+        assert!((heat_index(21.0, 50.0) - 20.5).abs() < 0.1);
+    }
+}
@@ -1,3 +1,7 @@
+use crate::error::{Result, WmError};
+use crate::network::wifi::StaticIp;
+use std::net::Ipv4Addr;
+
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -5,9 +9,186 @@ pub struct Config {
     #[default("")]
     wifi_psk: &'static str,
     #[default("")]
+    wifi_ssid_fallback: &'static str,
+    #[default("")]
+    wifi_psk_fallback: &'static str,
+    #[default("")]
+    static_ip: &'static str,
+    #[default("")]
+    static_gateway: &'static str,
+    #[default("255.255.255.0")]
+    static_netmask: &'static str,
+    // Empty to use the gateway as the DNS server
+    #[default("")]
+    static_dns: &'static str,
+    // Empty to use the default pool.ntp.org servers
+    #[default("")]
+    pub ntp_server: &'static str,
+    #[default(10)]
+    pub ntp_timeout_secs: u32,
+    // Advertised over mDNS as `<hostname>.local`, which only resolves on
+    // clients with mDNS support (macOS, most Linux desktops, Windows 10+)
+    #[default("wm4esp")]
+    pub hostname: &'static str,
+    #[default("")]
+    pub http_username: &'static str,
+    #[default("")]
+    pub http_password: &'static str,
+    #[default("")]
+    pub mqtt_host: &'static str,
+    #[default(1883)]
+    pub mqtt_port: u16,
+    #[default("")]
+    pub mqtt_username: &'static str,
+    #[default("")]
+    pub mqtt_password: &'static str,
+    #[default("wm4esp/sensor")]
+    pub mqtt_topic: &'static str,
+    #[default("")]
     pub qweather_key: &'static str,
+    // QWeather LocationID, looked up from `city` when left empty
     #[default("")]
     pub location: &'static str,
     #[default("")]
     pub city: &'static str,
+    // Panel size in pixels, the width must be a multiple of 8
+    #[default(400)]
+    pub screen_width: u32,
+    #[default(300)]
+    pub screen_height: u32,
+    // Language of the panel labels and weather texts, "zh" or "en"
+    #[default("zh")]
+    pub language: &'static str,
+    // Shows SO2, NO2, CO and O3 in the banner in place of precipitation,
+    // feels like, pressure and UV
+    #[default(false)]
+    pub air_details: bool,
+    // Plots the daily highs and lows of the week instead of listing three days
+    #[default(false)]
+    pub forecast_chart: bool,
+    // Pixels of each 2x2 block that must be set when the forecast icons are
+    // halved to 32px, from 1 to 4. Lower keeps thin lines on faint panels
+    #[default(3)]
+    pub icon_threshold: u8,
+    // "metric" for °C and hPa, "imperial" for °F and inHg
+    #[default("metric")]
+    pub units: &'static str,
+    // Every Nth refresh is a full one that clears ghosting, the others use
+    // the faster waveform. 1 (or 0) makes every refresh a full one
+    #[default(1)]
+    pub full_refresh_every: u32,
+    // Draws white text on a black background
+    #[default(false)]
+    pub invert_colors: bool,
+    // Indoor sensor on the I2C bus, "dht20" or "bme280"
+    #[default("dht20")]
+    pub sensor_type: &'static str,
+    // I2C address of the DHT20, 0x38 unless changed on the board
+    #[default(0x38)]
+    pub dht20_address: u8,
+    // I2C address of the BME280, 0x76 or 0x77 with SDO pulled high
+    #[default(0x76)]
+    pub bme280_address: u8,
+    #[default(1000000)]
+    pub i2c_baud_rate: u32,
+    // SPI clock of the panel, lower it if long wires corrupt the frames
+    #[default(20000000)]
+    pub spi_baud_rate: u32,
+    // Moves the panel transfers to a DMA channel, off until tried on a panel
+    #[default(false)]
+    pub spi_dma: bool,
+    // GPIO of the status LED, negative when there is none
+    #[default(-1)]
+    pub led_pin: i32,
+    // GPIO of the manual refresh button, negative when there is none
+    #[default(-1)]
+    pub button_pin: i32,
+    // ADC1 pin wired to the battery divider, 0 when running without one
+    #[default(0)]
+    pub battery_pin: u8,
+    // Below this cell voltage the panel shows a warning and stops refreshing
+    // until the cell recovers, 0 disables the check
+    #[default(3400)]
+    pub low_battery_millivolts: u32,
+    // Reboots when the app loop stalls this long, 0 disables the watchdog.
+    // A full refresh plus a weather update with retries can take minutes
+    #[default(300)]
+    pub watchdog_timeout_secs: u32,
+    #[default(8)]
+    pub utc_offset_hours: i8,
+    #[default(7)]
+    pub refresh_start_hour: u8,
+    #[default(23)]
+    pub refresh_end_hour: u8,
+    #[default(60)]
+    pub refresh_interval_minutes: u32,
+    // Also refreshes when the weather text changes or the temperature moves
+    // by more than this many °C since the last refresh, 0 disables the checks.
+    // Enabling them costs an extra weather fetch every 15 minutes
+    #[default(0)]
+    pub weather_change_threshold: u32,
+}
+
+impl Config {
+    /// Known networks in order of preference, leaving out unset entries.
+    pub fn wifi_networks(&self) -> Vec<(&'static str, &'static str)> {
+        [
+            (self.wifi_ssid, self.wifi_psk),
+            (self.wifi_ssid_fallback, self.wifi_psk_fallback),
+        ]
+        .into_iter()
+        .filter(|(ssid, _)| !ssid.is_empty())
+        .collect()
+    }
+
+    /// Fails with every required key that is left empty, so a first flash
+    /// reports all of them at once instead of failing later without a hint.
+    /// The weather key and location only draw a warning, since `/config`
+    /// can still provide them and they are kept in NVS from then on.
+    pub fn validate(&self) -> Result<()> {
+        let mut missing = Vec::new();
+        if self.wifi_networks().is_empty() {
+            missing.push("wifi_ssid");
+        }
+        if self.qweather_key.trim().is_empty() {
+            println!("qweather_key is empty, no weather until it is set through /config");
+        }
+        if self.location.trim().is_empty() && self.city.trim().is_empty() {
+            println!("location and city are empty, no weather until /config sets a location");
+        }
+        if !matches!(self.sensor_type, "dht20" | "bme280") {
+            missing.push("sensor_type");
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(WmError::InvalidConfig(missing.join(", ")))
+        }
+    }
+
+    /// Static IPv4 settings, or None to use DHCP when `static_ip` is empty.
+    pub fn static_ip(&self) -> Result<Option<StaticIp>> {
+        if self.static_ip.is_empty() {
+            return Ok(None);
+        }
+        let parse = |addr: &str| addr.parse().map_err(|_| WmError::InvalidArgument);
+        let netmask: Ipv4Addr = parse(self.static_netmask)?;
+        // Only a run of leading ones can be passed on as a prefix length
+        let bits = u32::from(netmask);
+        if bits.leading_ones() != bits.count_ones() {
+            return Err(WmError::InvalidConfig("static_netmask".to_string()));
+        }
+        let gateway = parse(self.static_gateway)?;
+        let dns = if self.static_dns.is_empty() {
+            gateway
+        } else {
+            parse(self.static_dns)?
+        };
+        Ok(Some(StaticIp {
+            ip: parse(self.static_ip)?,
+            gateway,
+            netmask,
+            dns,
+        }))
+    }
 }
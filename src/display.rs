@@ -3,21 +3,18 @@ use embedded_graphics::{
     pixelcolor::raw::{RawData, RawU2},
     pixelcolor::PixelColor,
     prelude::*,
+    primitives::Rectangle,
 };
+use std::io::Write;
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
 pub enum Color {
+    #[default]
     White,
     Black,
     Red,
 }
 
-impl Default for Color {
-    fn default() -> Self {
-        Self::White
-    }
-}
-
 impl PixelColor for Color {
     type Raw = RawU2;
 }
@@ -42,20 +39,68 @@ impl From<Color> for RawU2 {
     }
 }
 
+/// Gray levels used by the 4-level grayscale mode. The level is stored as
+/// two bits per pixel, using the black plane as the low bit and the red plane
+/// as the high bit, so White and Black match their `Color` counterparts.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum GrayColor {
+    #[default]
+    White,
+    Black,
+    LightGray,
+    DarkGray,
+}
+
+impl PixelColor for GrayColor {
+    type Raw = RawU2;
+}
+
+impl From<RawU2> for GrayColor {
+    fn from(data: RawU2) -> Self {
+        match data.into_inner() {
+            0 => GrayColor::White,
+            1 => GrayColor::Black,
+            2 => GrayColor::LightGray,
+            _ => GrayColor::DarkGray,
+        }
+    }
+}
+
+impl From<GrayColor> for RawU2 {
+    fn from(color: GrayColor) -> Self {
+        match color {
+            GrayColor::White => RawU2::new(0),
+            GrayColor::Black => RawU2::new(1),
+            GrayColor::LightGray => RawU2::new(2),
+            GrayColor::DarkGray => RawU2::new(3),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Rotation {
+    #[default]
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// Tri-color framebuffer for the e-paper panel. All text is drawn through its
+/// `DrawTarget` impl with `u8g2_fonts`, there is no separate bitmap font path.
 pub struct Display {
     width: usize,
     height: usize,
     black_bitmap: Vec<u8>,
     red_bitmap: Vec<u8>,
     border_color: Color,
+    rotation: Rotation,
 }
 
 impl Display {
     pub fn new(width: usize, height: usize, border_color: Color) -> Self {
-        let mut black_bitmap = Vec::new();
-        black_bitmap.resize(height * width / 8, 0);
-        let mut red_bitmap = Vec::new();
-        red_bitmap.resize(height * width / 8, 0);
+        let mut black_bitmap = vec![0; (height * width).div_ceil(8)];
+        let mut red_bitmap = vec![0; (height * width).div_ceil(8)];
         match border_color {
             Color::Black => black_bitmap.fill(0xff),
             Color::Red => red_bitmap.fill(0xff),
@@ -67,9 +112,18 @@ impl Display {
             black_bitmap,
             red_bitmap,
             border_color,
+            rotation: Rotation::default(),
         }
     }
 
+    /// Rotates the drawing coordinates clockwise. The framebuffer keeps the
+    /// panel orientation, so `get_width`/`get_height` still report the panel
+    /// dimensions while `size()` reports the rotated ones.
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
     pub fn get_height(&self) -> usize {
         self.height
     }
@@ -78,10 +132,27 @@ impl Display {
         self.width
     }
 
+    pub fn get_rotation(&self) -> Rotation {
+        self.rotation
+    }
+
     pub fn get_border_color(&self) -> Color {
         self.border_color
     }
 
+    /// The raw black plane in panel coordinates, one bit per pixel at
+    /// `x + y * width`, least significant bit first. Together with `red_plane`
+    /// this lets a rendered frame be inspected or compared without a panel.
+    pub fn black_plane(&self) -> &[u8] {
+        &self.black_bitmap
+    }
+
+    /// The raw red plane, laid out like `black_plane`. A pixel set in both
+    /// planes reads as black.
+    pub fn red_plane(&self) -> &[u8] {
+        &self.red_bitmap
+    }
+
     pub fn clear(&mut self, color: Color) {
         self.black_bitmap.fill(0);
         self.red_bitmap.fill(0);
@@ -92,11 +163,52 @@ impl Display {
         };
     }
 
-    fn set_pixel(&mut self, x: usize, y: usize, color: Color) -> Result<()> {
-        if x >= self.width || y >= self.height {
+    /// Fills only the `width` x `height` rectangle at `(x, y)` with `color`,
+    /// leaving the rest of the frame untouched. The rectangle must lie inside
+    /// the drawing area.
+    pub fn clear_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: Color,
+    ) -> Result<()> {
+        let (screen_width, screen_height) = self.logical_size();
+        if x + width > screen_width || y + height > screen_height {
             return Err(WmError::InvalidArgument);
         }
-        let pos = x + y * self.width;
+        let area = Rectangle::new(
+            Point::new(x as i32, y as i32),
+            Size::new(width as u32, height as u32),
+        );
+        // Red on its own doesn't clear the black bit, so start from white
+        self.fill_solid(&area, Color::White)?;
+        if color != Color::White {
+            self.fill_solid(&area, color)?;
+        }
+        Ok(())
+    }
+
+    /// Turns the frame into its negative: white pixels become black and black
+    /// ones white, red stays red. The border color is swapped the same way so
+    /// the panel edge matches the new background.
+    pub fn invert(&mut self) {
+        for (black, red) in self.black_bitmap.iter_mut().zip(self.red_bitmap.iter_mut()) {
+            // Black takes priority over red when both bits are set
+            let new_black = !(*black | *red);
+            *red &= !*black;
+            *black = new_black;
+        }
+        self.border_color = match self.border_color {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+            Color::Red => Color::Red,
+        };
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Color) -> Result<()> {
+        let pos = self.pixel_index(x, y)?;
         match color {
             Color::Black => self.black_bitmap[pos / 8] |= 1u8 << (pos % 8),
             Color::Red => self.red_bitmap[pos / 8] |= 1u8 << (pos % 8),
@@ -109,19 +221,91 @@ impl Display {
     }
 
     pub fn get_pixel(&self, x: usize, y: usize) -> Result<Color> {
+        let pos = self.pixel_index(x, y)?;
+        Ok(self.pixel_at(pos))
+    }
+
+    /// Reads a pixel in panel coordinates, ignoring the rotation.
+    pub fn get_panel_pixel(&self, x: usize, y: usize) -> Result<Color> {
         if x >= self.width || y >= self.height {
             return Err(WmError::InvalidArgument);
         }
-        let pos = x + y * self.width;
+        Ok(self.pixel_at(x + y * self.width))
+    }
+
+    fn pixel_at(&self, pos: usize) -> Color {
         if self.black_bitmap[pos / 8] & (1u8 << (pos % 8)) != 0 {
-            return Ok(Color::Black);
+            Color::Black
         } else if self.red_bitmap[pos / 8] & (1u8 << (pos % 8)) != 0 {
-            return Ok(Color::Red);
+            Color::Red
         } else {
-            return Ok(Color::White);
+            Color::White
+        }
+    }
+
+    fn logical_size(&self) -> (usize, usize) {
+        match self.rotation {
+            Rotation::Rotate0 | Rotation::Rotate180 => (self.width, self.height),
+            Rotation::Rotate90 | Rotation::Rotate270 => (self.height, self.width),
         }
     }
 
+    fn pixel_index(&self, x: usize, y: usize) -> Result<usize> {
+        let (width, height) = self.logical_size();
+        if x >= width || y >= height {
+            return Err(WmError::InvalidArgument);
+        }
+        let (x, y) = match self.rotation {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (self.width - 1 - y, x),
+            Rotation::Rotate180 => (self.width - 1 - x, self.height - 1 - y),
+            Rotation::Rotate270 => (y, self.height - 1 - x),
+        };
+        Ok(x + y * self.width)
+    }
+
+    pub fn set_gray_pixel(&mut self, x: usize, y: usize, color: GrayColor) -> Result<()> {
+        let pos = self.pixel_index(x, y)?;
+        let level = RawU2::from(color).into_inner();
+        let mask = 1u8 << (pos % 8);
+        if level & 0x1 != 0 {
+            self.black_bitmap[pos / 8] |= mask;
+        } else {
+            self.black_bitmap[pos / 8] &= !mask;
+        }
+        if level & 0x2 != 0 {
+            self.red_bitmap[pos / 8] |= mask;
+        } else {
+            self.red_bitmap[pos / 8] &= !mask;
+        }
+        Ok(())
+    }
+
+    pub fn get_gray_pixel(&self, x: usize, y: usize) -> Result<GrayColor> {
+        let pos = self.pixel_index(x, y)?;
+        Ok(self.gray_pixel_at(pos))
+    }
+
+    /// Reads a gray level in panel coordinates, ignoring the rotation.
+    pub fn get_panel_gray_pixel(&self, x: usize, y: usize) -> Result<GrayColor> {
+        if x >= self.width || y >= self.height {
+            return Err(WmError::InvalidArgument);
+        }
+        Ok(self.gray_pixel_at(x + y * self.width))
+    }
+
+    fn gray_pixel_at(&self, pos: usize) -> GrayColor {
+        let mask = 1u8 << (pos % 8);
+        let mut level = 0;
+        if self.black_bitmap[pos / 8] & mask != 0 {
+            level |= 0x1;
+        }
+        if self.red_bitmap[pos / 8] & mask != 0 {
+            level |= 0x2;
+        }
+        RawU2::new(level).into()
+    }
+
     pub fn bitmap(
         &mut self,
         x: usize,
@@ -131,7 +315,39 @@ impl Display {
         bitmap: &[u8],
         color: Color,
     ) -> Result<()> {
-        if height * width / 8 != bitmap.len() || x + width > self.width || y + height > self.height
+        self.draw_bitmap(x, y, width, height, bitmap, color, false)
+    }
+
+    /// Like `bitmap`, but sets `color` where the source bit is 0. Drawn over
+    /// a filled block this leaves the glyph in the block color, knocked out
+    /// of a `color` background.
+    pub fn bitmap_inverted(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        bitmap: &[u8],
+        color: Color,
+    ) -> Result<()> {
+        self.draw_bitmap(x, y, width, height, bitmap, color, true)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn draw_bitmap(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        bitmap: &[u8],
+        color: Color,
+        inverted: bool,
+    ) -> Result<()> {
+        let (screen_width, screen_height) = self.logical_size();
+        if height * width / 8 != bitmap.len()
+            || x + width > screen_width
+            || y + height > screen_height
         {
             return Err(WmError::InvalidArgument);
         }
@@ -139,13 +355,288 @@ impl Display {
             for bmp_y in 0..height {
                 let pos = bmp_x / 8 + bmp_y * (width / 8);
                 let pattern = 1u8 << (7 - (bmp_x % 8));
-                if bitmap[pos] & pattern != 0 {
+                if (bitmap[pos] & pattern != 0) != inverted {
                     self.set_pixel(x + bmp_x, y + bmp_y, color)?;
                 }
             }
         }
         Ok(())
     }
+
+    /// Shrinks a bitmap in the layout `bitmap` takes by `factor` in both
+    /// directions. Each output pixel covers a `factor` x `factor` block and is
+    /// set when at least `threshold` pixels of the block are set. The scaled
+    /// width must stay a multiple of 8.
+    pub fn downscale(
+        bitmap: &[u8],
+        width: usize,
+        height: usize,
+        factor: usize,
+        threshold: usize,
+    ) -> Result<Vec<u8>> {
+        if factor == 0
+            || !width.is_multiple_of(factor)
+            || !height.is_multiple_of(factor)
+            || !(width / factor).is_multiple_of(8)
+            || height * width / 8 != bitmap.len()
+        {
+            return Err(WmError::InvalidArgument);
+        }
+        let (new_width, new_height) = (width / factor, height / factor);
+        let mut scaled = vec![0u8; new_width * new_height / 8];
+        for y in 0..new_height {
+            for x in 0..new_width {
+                let mut count = 0;
+                for src_y in y * factor..(y + 1) * factor {
+                    for src_x in x * factor..(x + 1) * factor {
+                        let pos = src_x + src_y * width;
+                        if bitmap[pos / 8] & (1u8 << (7 - pos % 8)) != 0 {
+                            count += 1;
+                        }
+                    }
+                }
+                if count >= threshold {
+                    let pos = x + y * new_width;
+                    scaled[pos / 8] |= 1u8 << (7 - pos % 8);
+                }
+            }
+        }
+        Ok(scaled)
+    }
+
+    // The line is clipped to the display first, so only the visible portion
+    // is walked and far away endpoints can't overflow.
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) -> Result<()> {
+        let (width, height) = self.logical_size();
+        let max = (width as i64 - 1, height as i64 - 1);
+        let ((x0, y0), (x1, y1)) =
+            match clip_line((x0 as i64, y0 as i64), (x1 as i64, y1 as i64), max) {
+                // Both ends are on the display now, so they fit in i32
+                Some(((x0, y0), (x1, y1))) => ((x0 as i32, y0 as i32), (x1 as i32, y1 as i32)),
+                None => return Ok(()),
+            };
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            self.set_pixel_clipped(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn circle(&mut self, x: i32, y: i32, radius: i32, color: Color) -> Result<()> {
+        if radius < 0 {
+            return Err(WmError::InvalidArgument);
+        }
+        let (mut dx, mut dy) = (radius, 0);
+        let mut err = 1 - radius;
+        while dx >= dy {
+            for (px, py) in [
+                (dx, dy),
+                (dy, dx),
+                (-dy, dx),
+                (-dx, dy),
+                (-dx, -dy),
+                (-dy, -dx),
+                (dy, -dx),
+                (dx, -dy),
+            ] {
+                self.set_pixel_clipped(x + px, y + py, color);
+            }
+            dy += 1;
+            if err < 0 {
+                err += 2 * dy + 1;
+            } else {
+                dx -= 1;
+                err += 2 * (dy - dx) + 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn filled_circle(&mut self, x: i32, y: i32, radius: i32, color: Color) -> Result<()> {
+        if radius < 0 {
+            return Err(WmError::InvalidArgument);
+        }
+        let (mut dx, mut dy) = (radius, 0);
+        let mut err = 1 - radius;
+        while dx >= dy {
+            self.hline_clipped(x - dx, x + dx, y + dy, color);
+            self.hline_clipped(x - dx, x + dx, y - dy, color);
+            self.hline_clipped(x - dy, x + dy, y + dx, color);
+            self.hline_clipped(x - dy, x + dy, y - dx, color);
+            dy += 1;
+            if err < 0 {
+                err += 2 * dy + 1;
+            } else {
+                dx -= 1;
+                err += 2 * (dy - dx) + 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn hline_clipped(&mut self, x0: i32, x1: i32, y: i32, color: Color) {
+        let (width, height) = self.logical_size();
+        if y < 0 || y >= height as i32 {
+            return;
+        }
+        let x0 = x0.max(0);
+        let x1 = x1.min(width as i32 - 1);
+        for x in x0..=x1 {
+            let _ = self.set_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    /// Encodes the framebuffer as an indexed PNG with a white/black/red
+    /// palette, in the rotated orientation.
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let (width, height) = self.logical_size();
+        let row_bytes = width.div_ceil(4);
+        let mut raw = Vec::with_capacity((row_bytes + 1) * height);
+        for y in 0..height {
+            // Filter type None
+            raw.push(0);
+            let mut row = vec![0u8; row_bytes];
+            for x in 0..width {
+                let index = RawU2::from(self.get_pixel(x, y)?).into_inner();
+                row[x / 4] |= index << (6 - 2 * (x % 4));
+            }
+            raw.extend_from_slice(&row);
+        }
+        let mut encoder = libflate::zlib::Encoder::new(Vec::new())?;
+        encoder.write_all(&raw)?;
+        let data = encoder.finish().into_result()?;
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        // 2-bit depth, indexed color, deflate, no filter, no interlace
+        header.extend_from_slice(&[2, 3, 0, 0, 0]);
+
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        write_png_chunk(&mut png, b"IHDR", &header);
+        write_png_chunk(&mut png, b"PLTE", &[0xFF, 0xFF, 0xFF, 0, 0, 0, 0xFF, 0, 0]);
+        write_png_chunk(&mut png, b"IDAT", &data);
+        write_png_chunk(&mut png, b"IEND", &[]);
+        Ok(png)
+    }
+
+    fn set_pixel_clipped(&mut self, x: i32, y: i32, color: Color) {
+        if x >= 0 && y >= 0 {
+            let _ = self.set_pixel(x as usize, y as usize, color);
+        }
+    }
+}
+
+/// Cohen-Sutherland clipping of the segment `p0`-`p1` to the pixels from the
+/// origin to `max`, inclusive. None when no part of it is inside.
+fn clip_line(p0: (i64, i64), p1: (i64, i64), max: (i64, i64)) -> Option<((i64, i64), (i64, i64))> {
+    const LEFT: u8 = 1;
+    const RIGHT: u8 = 2;
+    const TOP: u8 = 4;
+    const BOTTOM: u8 = 8;
+    // Clipping at the outer edges of the border pixels rather than their
+    // centers keeps the pixels Bresenham would round onto the display
+    let (left, top) = (-0.5, -0.5);
+    let (right, bottom) = (max.0 as f64 + 0.5, max.1 as f64 + 0.5);
+    let outcode = |(x, y): (f64, f64)| {
+        let mut code = 0;
+        if x < left {
+            code |= LEFT;
+        } else if x > right {
+            code |= RIGHT;
+        }
+        if y < top {
+            code |= TOP;
+        } else if y > bottom {
+            code |= BOTTOM;
+        }
+        code
+    };
+    // Intersections are taken on the original segment, so the error doesn't
+    // add up over several clips
+    let (x0, y0) = (p0.0 as f64, p0.1 as f64);
+    let (x1, y1) = (p1.0 as f64, p1.1 as f64);
+    let x_at = |y: f64| x0 + (x1 - x0) * (y - y0) / (y1 - y0);
+    let y_at = |x: f64| y0 + (y1 - y0) * (x - x0) / (x1 - x0);
+    let (mut p0, mut p1) = ((x0, y0), (x1, y1));
+    let (mut code0, mut code1) = (outcode(p0), outcode(p1));
+    // Each end is clipped twice at most, the bound only stops a rounding
+    // error just outside a corner from bouncing between two edges
+    for _ in 0..8 {
+        if code0 | code1 == 0 {
+            let pixel = |(x, y): (f64, f64)| {
+                (
+                    (x.round() as i64).clamp(0, max.0),
+                    (y.round() as i64).clamp(0, max.1),
+                )
+            };
+            return Some((pixel(p0), pixel(p1)));
+        }
+        if code0 & code1 != 0 {
+            return None;
+        }
+        // Both ends being on the same side was ruled out above, so the
+        // divisions are never by zero
+        let code = if code0 != 0 { code0 } else { code1 };
+        let point = if code & TOP != 0 {
+            (x_at(top), top)
+        } else if code & BOTTOM != 0 {
+            (x_at(bottom), bottom)
+        } else if code & LEFT != 0 {
+            (left, y_at(left))
+        } else {
+            (right, y_at(right))
+        };
+        if code == code0 {
+            p0 = point;
+            code0 = outcode(p0);
+        } else {
+            p1 = point;
+            code1 = outcode(p1);
+        }
+    }
+    None
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB88320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
 }
 
 impl DrawTarget for Display {
@@ -156,9 +647,12 @@ impl DrawTarget for Display {
     where
         I: IntoIterator<Item = Pixel<Self::Color>>,
     {
+        let (width, height) = self.logical_size();
         for Pixel(coord, color) in pixels.into_iter() {
-            if let Ok((x @ 0..=399, y @ 0..=299)) = coord.try_into() {
-                self.set_pixel(x as usize, y as usize, color)?;
+            if let Ok((x, y)) = TryInto::<(u32, u32)>::try_into(coord) {
+                if (x as usize) < width && (y as usize) < height {
+                    self.set_pixel(x as usize, y as usize, color)?;
+                }
             }
         }
         Ok(())
@@ -167,6 +661,353 @@ impl DrawTarget for Display {
 
 impl OriginDimensions for Display {
     fn size(&self) -> Size {
-        Size::new(self.width as u32, self.height as u32)
+        let (width, height) = self.logical_size();
+        Size::new(width as u32, height as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs a `width` x `height` bitmap in the layout `bitmap` takes.
+    fn pattern(width: usize, height: usize, set: impl Fn(usize, usize) -> bool) -> Vec<u8> {
+        let mut bitmap = vec![0u8; width * height / 8];
+        for y in 0..height {
+            for x in 0..width {
+                if set(x, y) {
+                    let pos = x + y * width;
+                    bitmap[pos / 8] |= 1u8 << (7 - pos % 8);
+                }
+            }
+        }
+        bitmap
+    }
+
+    fn count_bits(data: &[u8]) -> u32 {
+        data.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    #[test]
+    fn planes_follow_drawn_pixels() {
+        let mut display = Display::new(16, 2, Color::White);
+        Pixel(Point::new(0, 0), Color::Black)
+            .draw(&mut display)
+            .unwrap();
+        Pixel(Point::new(9, 0), Color::Red)
+            .draw(&mut display)
+            .unwrap();
+        Pixel(Point::new(1, 1), Color::Black)
+            .draw(&mut display)
+            .unwrap();
+        assert_eq!(display.black_plane(), &[0x01, 0x00, 0x02, 0x00]);
+        assert_eq!(display.red_plane(), &[0x00, 0x02, 0x00, 0x00]);
+
+        Pixel(Point::new(0, 0), Color::White)
+            .draw(&mut display)
+            .unwrap();
+        assert_eq!(display.black_plane(), &[0x00, 0x00, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn clear_fills_one_plane() {
+        let mut display = Display::new(8, 2, Color::Red);
+        assert_eq!(display.black_plane(), &[0x00, 0x00]);
+        assert_eq!(display.red_plane(), &[0xff, 0xff]);
+
+        display.clear(Color::Black);
+        assert_eq!(display.black_plane(), &[0xff, 0xff]);
+        assert_eq!(display.red_plane(), &[0x00, 0x00]);
+    }
+
+    #[test]
+    fn downscale_threshold() {
+        // Eight 2x2 blocks with this many pixels set each
+        let counts = [0, 1, 2, 3, 4, 4, 3, 1];
+        let bitmap = pattern(16, 2, |x, y| (x % 2) + 2 * y < counts[x / 2]);
+        assert_eq!(count_bits(&bitmap), 18);
+        for (threshold, expected) in [(1, 7), (2, 5), (3, 4), (4, 2)] {
+            let scaled = Display::downscale(&bitmap, 16, 2, 2, threshold).unwrap();
+            assert_eq!(count_bits(&scaled), expected, "threshold {}", threshold);
+        }
+        // Blocks are kept from the left, the most significant bit first
+        let scaled = Display::downscale(&bitmap, 16, 2, 2, 4).unwrap();
+        assert_eq!(scaled, vec![0b0000_1100]);
+    }
+
+    fn set_pixels(display: &Display) -> Vec<(usize, usize)> {
+        let mut pixels = Vec::new();
+        for y in 0..display.get_height() {
+            for x in 0..display.get_width() {
+                if display.get_pixel(x, y).unwrap() != Color::White {
+                    pixels.push((x, y));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn line_diagonal() {
+        let mut display = Display::new(16, 16, Color::White);
+        display.line(2, 3, 12, 13, Color::Black).unwrap();
+        let expected: Vec<_> = (0..=10).map(|i| (2 + i, 3 + i)).collect();
+        assert_eq!(set_pixels(&display), expected);
+    }
+
+    #[test]
+    fn line_steep() {
+        let mut display = Display::new(16, 16, Color::White);
+        display.line(4, 15, 6, 0, Color::Red).unwrap();
+        let pixels = set_pixels(&display);
+        // One pixel per row, moving right without gaps
+        assert_eq!(pixels.len(), 16);
+        for (y, (x, row)) in pixels.iter().enumerate() {
+            assert_eq!(*row, y);
+            assert!((4..=6).contains(x));
+        }
+        assert_eq!((pixels[0], pixels[15]), ((6, 0), (4, 15)));
+        for pair in pixels.windows(2) {
+            assert!(pair[0].0 >= pair[1].0 && pair[0].0 - pair[1].0 <= 1);
+        }
+    }
+
+    #[test]
+    fn line_clipped() {
+        let mut display = Display::new(16, 8, Color::White);
+        // Walking this unclipped would take billions of steps and overflow
+        display
+            .line(i32::MIN, 2, i32::MAX, 2, Color::Black)
+            .unwrap();
+        let expected: Vec<_> = (0..16).map(|x| (x, 2)).collect();
+        assert_eq!(set_pixels(&display), expected);
+
+        display.clear(Color::White);
+        display.line(-8, -8, 7, 7, Color::Black).unwrap();
+        let expected: Vec<_> = (0..8).map(|i| (i, i)).collect();
+        assert_eq!(set_pixels(&display), expected);
+
+        // Entirely off the display
+        display.clear(Color::White);
+        display.line(-5, 20, 30, 9, Color::Black).unwrap();
+        display
+            .line(i32::MIN, i32::MIN, i32::MAX, i32::MIN, Color::Black)
+            .unwrap();
+        assert!(set_pixels(&display).is_empty());
+    }
+
+    #[test]
+    fn circle_small_radius() {
+        let mut display = Display::new(8, 8, Color::White);
+        display.circle(3, 3, 0, Color::Black).unwrap();
+        assert_eq!(set_pixels(&display), vec![(3, 3)]);
+        display.clear(Color::White);
+        display.filled_circle(3, 3, 0, Color::Black).unwrap();
+        assert_eq!(set_pixels(&display), vec![(3, 3)]);
+
+        display.clear(Color::White);
+        display.circle(3, 3, 1, Color::Black).unwrap();
+        assert_eq!(set_pixels(&display), vec![(3, 2), (2, 3), (4, 3), (3, 4)]);
+        display.clear(Color::White);
+        display.filled_circle(3, 3, 1, Color::Black).unwrap();
+        assert_eq!(
+            set_pixels(&display),
+            vec![(3, 2), (2, 3), (3, 3), (4, 3), (3, 4)]
+        );
+
+        assert!(display.circle(3, 3, -1, Color::Black).is_err());
+        assert!(display.filled_circle(3, 3, -1, Color::Black).is_err());
+    }
+
+    #[test]
+    fn circle_radius_10() {
+        let mut outline = Display::new(32, 32, Color::White);
+        outline.circle(16, 16, 10, Color::Black).unwrap();
+        let outline = set_pixels(&outline);
+        assert_eq!(outline.len(), 56);
+        for (x, y) in outline.iter() {
+            // Mirrored in both axes around the center
+            assert!(outline.contains(&(32 - x, *y)));
+            assert!(outline.contains(&(*x, 32 - y)));
+        }
+
+        let mut filled = Display::new(32, 32, Color::White);
+        filled.filled_circle(16, 16, 10, Color::Black).unwrap();
+        let filled = set_pixels(&filled);
+        assert_eq!(filled.len(), 349);
+        assert!(outline.iter().all(|pixel| filled.contains(pixel)));
+    }
+
+    #[test]
+    fn circle_clipped_at_edge() {
+        // Only the quarter around the bottom left corner is on the display
+        let mut display = Display::new(32, 32, Color::White);
+        display.circle(0, 31, 10, Color::Black).unwrap();
+        assert_eq!(set_pixels(&display).len(), 15);
+        display.clear(Color::White);
+        display.filled_circle(0, 31, 10, Color::Red).unwrap();
+        assert_eq!(set_pixels(&display).len(), 98);
+
+        display.clear(Color::White);
+        display.circle(-20, -20, 5, Color::Black).unwrap();
+        display.filled_circle(50, 10, 5, Color::Black).unwrap();
+        assert!(set_pixels(&display).is_empty());
+    }
+
+    #[test]
+    fn rotation_maps_pixels() {
+        for (rotation, size, panel) in [
+            (Rotation::Rotate0, Size::new(16, 8), (1, 2)),
+            (Rotation::Rotate90, Size::new(8, 16), (13, 1)),
+            (Rotation::Rotate180, Size::new(16, 8), (14, 5)),
+            (Rotation::Rotate270, Size::new(8, 16), (2, 6)),
+        ] {
+            let mut display = Display::new(16, 8, Color::White).with_rotation(rotation);
+            assert_eq!(display.size(), size);
+            // The panel dimensions don't change with the rotation
+            assert_eq!((display.get_width(), display.get_height()), (16, 8));
+
+            Pixel(Point::new(1, 2), Color::Black)
+                .draw(&mut display)
+                .unwrap();
+            assert_eq!(display.get_pixel(1, 2).unwrap(), Color::Black);
+            assert_eq!(
+                display.get_panel_pixel(panel.0, panel.1).unwrap(),
+                Color::Black,
+                "{:?}",
+                rotation
+            );
+            assert_eq!(count_bits(display.black_plane()), 1);
+        }
+
+        let mut display = Display::new(16, 8, Color::White).with_rotation(Rotation::Rotate90);
+        // Beyond the rotated width, though inside the panel width
+        Pixel(Point::new(12, 0), Color::Black)
+            .draw(&mut display)
+            .unwrap();
+        assert!(display.get_pixel(12, 0).is_err());
+        assert_eq!(count_bits(display.black_plane()), 0);
+    }
+
+    #[test]
+    fn odd_width_stride() {
+        // 250 pixels per row, so rows don't start on a byte boundary
+        let mut display = Display::new(250, 122, Color::White);
+        assert_eq!(display.black_plane().len(), 3813);
+        Pixel(Point::new(0, 1), Color::Black)
+            .draw(&mut display)
+            .unwrap();
+        Pixel(Point::new(249, 121), Color::Red)
+            .draw(&mut display)
+            .unwrap();
+        assert_eq!(display.black_plane()[31], 1 << 2);
+        assert_eq!(display.red_plane()[3812], 1 << 3);
+        assert_eq!(display.get_panel_pixel(0, 1).unwrap(), Color::Black);
+        assert_eq!(display.get_panel_pixel(249, 0).unwrap(), Color::White);
+        assert_eq!(display.get_panel_pixel(249, 121).unwrap(), Color::Red);
+        assert!(display.get_panel_pixel(250, 0).is_err());
+        assert!(display.get_panel_pixel(0, 122).is_err());
+
+        let mut display = Display::new(296, 128, Color::White);
+        Pixel(Point::new(295, 127), Color::Black)
+            .draw(&mut display)
+            .unwrap();
+        assert_eq!(display.black_plane().len(), 4736);
+        assert_eq!(display.black_plane()[4735], 1 << 7);
+        assert_eq!(display.get_pixel(295, 127).unwrap(), Color::Black);
+        assert_eq!(count_bits(display.black_plane()), 1);
+    }
+
+    #[test]
+    fn downscale_factors() {
+        // Left half set: halving keeps the left half, quartering too
+        let bitmap = pattern(32, 4, |x, _| x < 16);
+        assert_eq!(
+            Display::downscale(&bitmap, 32, 4, 2, 3).unwrap(),
+            vec![0xff, 0x00, 0xff, 0x00]
+        );
+        assert_eq!(
+            Display::downscale(&bitmap, 32, 4, 4, 9).unwrap(),
+            vec![0xf0]
+        );
+
+        // A checkerboard has two of four pixels set in every 2x2 block, so
+        // the default majority drops it and a threshold of two keeps it
+        let bitmap = pattern(16, 2, |x, y| (x + y) % 2 == 0);
+        assert_eq!(
+            Display::downscale(&bitmap, 16, 2, 2, 3).unwrap(),
+            vec![0x00]
+        );
+        assert_eq!(
+            Display::downscale(&bitmap, 16, 2, 2, 2).unwrap(),
+            vec![0xff]
+        );
+    }
+
+    #[test]
+    fn downscale_rejects_bad_sizes() {
+        let bitmap = vec![0u8; 24 * 3 / 8];
+        // Height not divisible by the factor
+        assert!(Display::downscale(&bitmap, 24, 3, 2, 3).is_err());
+        // Scaled width of 12 is not a multiple of 8
+        let bitmap = vec![0u8; 24 * 4 / 8];
+        assert!(Display::downscale(&bitmap, 24, 4, 2, 3).is_err());
+        // Width not divisible by the factor
+        assert!(Display::downscale(&bitmap, 24, 4, 5, 3).is_err());
+        assert!(Display::downscale(&bitmap, 24, 4, 0, 3).is_err());
+        // Bitmap shorter than the size says
+        assert!(Display::downscale(&bitmap[1..], 16, 6, 2, 3).is_err());
+    }
+
+    #[test]
+    fn clear_region_bounds() {
+        let mut display = Display::new(16, 8, Color::Black);
+        display.clear_region(2, 1, 4, 3, Color::Red).unwrap();
+        for y in 0..8 {
+            for x in 0..16 {
+                let inside = (2..6).contains(&x) && (1..4).contains(&y);
+                let expected = if inside { Color::Red } else { Color::Black };
+                assert_eq!(display.get_pixel(x, y).unwrap(), expected, "({}, {})", x, y);
+            }
+        }
+
+        // Reaching past the edge fails without touching the frame
+        assert!(display.clear_region(14, 0, 3, 1, Color::White).is_err());
+        assert!(display.clear_region(0, 6, 1, 3, Color::White).is_err());
+        assert_eq!(display.get_pixel(15, 0).unwrap(), Color::Black);
+        // Up to the edge is fine
+        display.clear_region(12, 4, 4, 4, Color::White).unwrap();
+        assert_eq!(display.get_pixel(15, 7).unwrap(), Color::White);
+
+        // The bounds follow the rotation
+        let mut display = Display::new(16, 8, Color::White).with_rotation(Rotation::Rotate90);
+        assert!(display.clear_region(0, 0, 16, 1, Color::Black).is_err());
+        display.clear_region(0, 0, 8, 16, Color::Black).unwrap();
+        assert_eq!(count_bits(display.black_plane()), 128);
+    }
+
+    #[test]
+    fn bitmap_inverted_swaps_bits() {
+        let bitmap = [0b1010_0000, 0b0000_1111];
+        let mut display = Display::new(8, 2, Color::White);
+        display.bitmap(0, 0, 8, 2, &bitmap, Color::Black).unwrap();
+        assert_eq!(
+            set_pixels(&display),
+            vec![(0, 0), (2, 0), (4, 1), (5, 1), (6, 1), (7, 1)]
+        );
+
+        // Set bits stay background, clear bits take the color
+        let mut display = Display::new(8, 2, Color::White);
+        display
+            .bitmap_inverted(0, 0, 8, 2, &bitmap, Color::Red)
+            .unwrap();
+        for (y, row) in bitmap.iter().enumerate() {
+            for x in 0..8 {
+                let set = row & (0x80 >> x) != 0;
+                let expected = if set { Color::White } else { Color::Red };
+                assert_eq!(display.get_pixel(x, y).unwrap(), expected, "({}, {})", x, y);
+            }
+        }
+        assert_eq!(count_bits(display.black_plane()), 0);
     }
 }
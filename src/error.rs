@@ -1,4 +1,6 @@
+#[cfg(feature = "esp")]
 use esp_idf_svc::errors::EspIOError;
+#[cfg(feature = "esp")]
 use esp_idf_sys::{self as _, EspError};
 
 use std::error;
@@ -10,11 +12,26 @@ pub type Result<T> = std::result::Result<T, WmError>;
 #[derive(Debug, Clone)]
 pub enum WmError {
     InvalidArgument,
+    #[cfg(feature = "esp")]
     EspError(EspError),
+    #[cfg(feature = "esp")]
     EspIOError(EspIOError),
     Utf8Error(Utf8Error),
     InternalError,
     GlyphNotFound(char),
+    HttpStatus(u16),
+    // serde_json errors are not Clone, so only the message (with the line and
+    // column) is kept
+    JsonError(String),
+    // Names of the missing or invalid config keys
+    InvalidConfig(String),
+    LocationLookup(String),
+    // The limit in bytes a response or request body exceeded
+    ResponseTooLarge(usize),
+    // Nothing acknowledged the I2C address of the indoor sensor
+    SensorMissing,
+    // SNTP didn't set the clock within the configured timeout
+    TimeSyncTimeout,
 }
 
 impl error::Error for WmError {}
@@ -23,15 +40,29 @@ impl fmt::Display for WmError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             WmError::InvalidArgument => write!(f, "Invalid Argument"),
+            #[cfg(feature = "esp")]
             WmError::EspError(error) => error.fmt(f),
+            #[cfg(feature = "esp")]
             WmError::EspIOError(error) => error.fmt(f),
             WmError::Utf8Error(error) => error.fmt(f),
             WmError::InternalError => write!(f, "Internal Error"),
             WmError::GlyphNotFound(ch) => write!(f, "GlyphNotFound '{}'", ch),
+            WmError::HttpStatus(status) => write!(f, "HTTP status {}", status),
+            WmError::JsonError(message) => write!(f, "JSON error: {}", message),
+            WmError::LocationLookup(reason) => write!(f, "Location lookup failed: {}", reason),
+            WmError::SensorMissing => write!(f, "Indoor sensor not responding"),
+            WmError::TimeSyncTimeout => write!(f, "Timed out waiting for SNTP"),
+            WmError::ResponseTooLarge(limit) => {
+                write!(f, "Response body larger than {} bytes", limit)
+            }
+            WmError::InvalidConfig(keys) => {
+                write!(f, "Invalid config, check {} in cfg.toml", keys)
+            }
         }
     }
 }
 
+#[cfg(feature = "esp")]
 impl From<EspError> for WmError {
     fn from(value: EspError) -> Self {
         WmError::EspError(value)
@@ -44,6 +75,7 @@ impl From<Utf8Error> for WmError {
     }
 }
 
+#[cfg(feature = "esp")]
 impl From<EspIOError> for WmError {
     fn from(value: EspIOError) -> Self {
         WmError::EspIOError(value)
@@ -51,8 +83,8 @@ impl From<EspIOError> for WmError {
 }
 
 impl From<serde_json::Error> for WmError {
-    fn from(_: serde_json::Error) -> Self {
-        WmError::InternalError
+    fn from(value: serde_json::Error) -> Self {
+        WmError::JsonError(value.to_string())
     }
 }
 
@@ -71,3 +103,11 @@ impl From<u8g2_fonts::Error<WmError>> for WmError {
         }
     }
 }
+
+impl From<u8g2_fonts::LookupError> for WmError {
+    fn from(value: u8g2_fonts::LookupError) -> Self {
+        match value {
+            u8g2_fonts::LookupError::GlyphNotFound(ch) => WmError::GlyphNotFound(ch),
+        }
+    }
+}
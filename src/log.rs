@@ -0,0 +1,28 @@
+use std::sync::Mutex;
+
+// Enough for a few hours of normal operation, at about 100 bytes a line
+const MAX_LINES: usize = 100;
+
+static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Prints `line` to the serial console and keeps it, prefixed with the
+/// uptime in seconds, for `/logs`. The oldest line is dropped once
+/// MAX_LINES are kept.
+pub fn push(line: impl AsRef<str>) {
+    let line = line.as_ref();
+    println!("{}", line);
+    let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } / 1000000;
+    let mut lines = LINES.lock().unwrap();
+    if lines.len() >= MAX_LINES {
+        lines.remove(0);
+    }
+    lines.push(format!("[{:>6}s] {}", uptime, line));
+}
+
+/// All kept lines, oldest first, one per line.
+pub fn dump() -> String {
+    let lines = LINES.lock().unwrap();
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
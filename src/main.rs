@@ -1,23 +1,62 @@
+// Host builds only exist to run the tests, which leaves most items unused
+#![cfg_attr(not(feature = "esp"), allow(dead_code, unused_imports))]
+
 mod app;
+mod climate;
+#[cfg(feature = "esp")]
 mod config;
 mod display;
 mod error;
+#[cfg(feature = "esp")]
+mod log;
 mod network;
 mod peripheral;
+#[cfg(feature = "esp")]
+mod settings;
 
+#[cfg(feature = "esp")]
 use esp_idf_sys::{self as _};
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 
+#[cfg(feature = "esp")]
+use esp_idf_hal::gpio::{AnyInputPin, AnyOutputPin};
+#[cfg(feature = "esp")]
 use esp_idf_hal::peripherals;
+#[cfg(feature = "esp")]
+use esp_idf_hal::units::Hertz;
+#[cfg(feature = "esp")]
 use esp_idf_svc::eventloop::EspSystemEventLoop;
+#[cfg(feature = "esp")]
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 
+#[cfg(feature = "esp")]
 use config::CONFIG;
+#[cfg(feature = "esp")]
 use network::wifi::WifiDevice;
+#[cfg(feature = "esp")]
+use peripheral::battery::Battery;
+#[cfg(feature = "esp")]
+use peripheral::bme280::Bme280;
+#[cfg(feature = "esp")]
+use peripheral::button::Button;
+use peripheral::device::Device;
+#[cfg(feature = "esp")]
 use peripheral::dht20::DHT20;
+#[cfg(feature = "esp")]
+use peripheral::led::Led;
+#[cfg(feature = "esp")]
+use peripheral::sensor::IndoorSensor;
+#[cfg(feature = "esp")]
 use peripheral::ssd1683::{SSD1683Gpio, SSD1683};
 use std::error::Error;
+use std::time::Duration;
+
+#[cfg(not(feature = "esp"))]
+fn main() {
+    println!("Built without the esp feature, nothing to run on the host");
+}
 
+#[cfg(feature = "esp")]
 fn main() -> std::result::Result<(), Box<dyn Error>> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
@@ -25,30 +64,110 @@ fn main() -> std::result::Result<(), Box<dyn Error>> {
     println!("Hello world from ESP 32 device");
 
     let conf = CONFIG;
+    if let Err(err) = conf.validate() {
+        println!("{}", err);
+        return Err(err.into());
+    }
     let peripherals = peripherals::Peripherals::take().unwrap();
     let eventloop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take().unwrap();
 
-    let mut wifi = WifiDevice::new(peripherals.modem, eventloop, Some(nvs))?;
-    wifi.connect(conf.wifi_ssid, conf.wifi_psk)?;
+    let led = if conf.led_pin >= 0 {
+        // SAFETY: the pin is taken from the config and must not be one of
+        // the pins handed out below
+        let pin = unsafe { AnyOutputPin::new(conf.led_pin) };
+        Some(Led::new(pin)?)
+    } else {
+        None
+    };
+
+    let mut wifi = WifiDevice::new(
+        peripherals.modem,
+        eventloop,
+        Some(nvs.clone()),
+        conf.static_ip()?,
+        conf.ntp_server,
+        Duration::from_secs(conf.ntp_timeout_secs as u64),
+    )?
+    .with_led(led.clone())
+    .with_hostname(conf.hostname);
+    let ssid = wifi.connect_any(&conf.wifi_networks())?;
+    println!("Connected to {}", ssid);
 
+    // Default wiring of the dev board:
+    // * BUSY -- GPIO12
+    // * RST  -- GPIO14
+    // * DC   -- GPIO13
+    // * CS   -- GPIO5
+    // * SCK  -- GPIO18
+    // * SDA  -- GPIO23
     let gpio = SSD1683Gpio {
-        gpio5: peripherals.pins.gpio5,
-        gpio12: peripherals.pins.gpio12,
-        gpio13: peripherals.pins.gpio13,
-        gpio14: peripherals.pins.gpio14,
-        gpio18: peripherals.pins.gpio18,
-        gpio23: peripherals.pins.gpio23,
+        busy: peripherals.pins.gpio12.into(),
+        reset: peripherals.pins.gpio14.into(),
+        dc: peripherals.pins.gpio13.into(),
+        cs: peripherals.pins.gpio5.into(),
+        sclk: peripherals.pins.gpio18.into(),
+        sdo: peripherals.pins.gpio23.into(),
     };
 
-    let ssd1683 = SSD1683::new(gpio, peripherals.spi2)?;
+    let panel: Box<dyn Device> = Box::new(
+        SSD1683::new(
+            gpio,
+            peripherals.spi2,
+            Hertz(conf.spi_baud_rate),
+            conf.spi_dma,
+        )?
+        .with_led(led),
+    );
 
-    let dht20 = DHT20::new(
+    let (i2c, sda, scl) = (
         peripherals.i2c1,
         peripherals.pins.gpio21,
         peripherals.pins.gpio22,
-    )?;
+    );
+    let sensor: Box<dyn IndoorSensor> = match conf.sensor_type {
+        "bme280" => Box::new(Bme280::new(
+            i2c,
+            sda,
+            scl,
+            conf.bme280_address,
+            Hertz(conf.i2c_baud_rate),
+        )?),
+        _ => Box::new(DHT20::new(
+            i2c,
+            sda,
+            scl,
+            conf.dht20_address,
+            Hertz(conf.i2c_baud_rate),
+        )?),
+    };
+
+    let battery = match conf.battery_pin {
+        0 => None,
+        32 => Some(Battery::new(peripherals.adc1, peripherals.pins.gpio32)?),
+        33 => Some(Battery::new(peripherals.adc1, peripherals.pins.gpio33)?),
+        34 => Some(Battery::new(peripherals.adc1, peripherals.pins.gpio34)?),
+        35 => Some(Battery::new(peripherals.adc1, peripherals.pins.gpio35)?),
+        36 => Some(Battery::new(peripherals.adc1, peripherals.pins.gpio36)?),
+        39 => Some(Battery::new(peripherals.adc1, peripherals.pins.gpio39)?),
+        pin => {
+            println!(
+                "GPIO{} has no ADC1 channel, battery monitoring disabled",
+                pin
+            );
+            None
+        }
+    };
+
+    let button = if conf.button_pin >= 0 {
+        // SAFETY: the pin is taken from the config and must not be one of
+        // the pins handed out above
+        let pin = unsafe { AnyInputPin::new(conf.button_pin) };
+        Some(Button::new(pin)?)
+    } else {
+        None
+    };
 
-    app::app_main(ssd1683, dht20, wifi, conf)?;
+    app::app_main(panel, sensor, battery, button, wifi, nvs, conf)?;
     Ok(())
 }
@@ -0,0 +1,101 @@
+/// Decodes an url-encoded form body into its name and value pairs.
+pub fn parse_form(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            Some((url_decode(key), url_decode(value)))
+        })
+        .collect()
+}
+
+/// Percent-encodes everything but unreserved characters, for query values.
+pub fn url_encode(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(byte as char)
+            }
+            byte => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
+
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => result.push(b' '),
+            b'%' if idx + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[idx + 1..idx + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        result.push(byte);
+                        idx += 2;
+                    }
+                    Err(_) => result.push(b'%'),
+                }
+            }
+            byte => result.push(byte),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Escapes text for use in HTML content or a quoted attribute value.
+pub fn escape_html(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            ch => result.push(ch),
+        }
+    }
+    result
+}
+
+/// The main page with `note` filled into its text area.
+pub fn render_index(note: &str) -> String {
+    include_str!("index.html").replace("[[[PLACEHOLDER]]]", &escape_html(note))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_is_escaped() {
+        let body = "sticky=%3C%2Ftextarea%3E%3Cscript%3Ealert(%22x%22)%3C%2Fscript%3E";
+        let (_, note) = parse_form(body).into_iter().next().unwrap();
+        assert_eq!(note, "</textarea><script>alert(\"x\")</script>");
+        let html = render_index(&note);
+        assert!(html.contains(
+            "&lt;/textarea&gt;&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;</textarea>"
+        ));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn escape_quotes_and_ampersands() {
+        assert_eq!(escape_html("a&b 'c'"), "a&amp;b &#39;c&#39;");
+        assert_eq!(escape_html("\"><x"), "&quot;&gt;&lt;x");
+    }
+
+    #[test]
+    fn form_round_trip() {
+        let value = "北京 a+b&c=d";
+        let body = format!("location={}", url_encode(value));
+        assert_eq!(
+            parse_form(&body),
+            vec![("location".to_string(), value.to_string())]
+        );
+    }
+}
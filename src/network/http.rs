@@ -1,4 +1,10 @@
-use crate::error::Result;
+use super::form::{escape_html, parse_form, render_index};
+use super::HttpGet;
+use crate::climate::{dew_point, heat_index};
+use crate::display::Display;
+use crate::error::{Result, WmError};
+use crate::log;
+use crate::settings::Settings;
 
 use embedded_svc::http::client::Client;
 use embedded_svc::http::{Headers, Status};
@@ -6,61 +12,239 @@ use embedded_svc::io::Read;
 use embedded_svc::{http::Method, io::Write};
 use esp_idf_svc::http::client::EspHttpConnection;
 use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::ota::EspOta;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::io::Read as _;
 use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 
+// Five minute samples for the last 48 hours
+const SENSOR_HISTORY_LEN: usize = 12 * 48;
+
+const UNAUTHORIZED_HEADER: (&str, &str) = ("WWW-Authenticate", "Basic realm=\"wm4esp\"");
+const HTML_CONTENT_TYPE: (&str, &str) = ("Content-Type", "text/html; charset=utf-8");
+const JSON_CONTENT_TYPE: (&str, &str) = ("Content-Type", "application/json");
+const TEXT_CONTENT_TYPE: (&str, &str) = ("Content-Type", "text/plain; charset=utf-8");
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RETRIES: u32 = 2;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_REDIRECTS: usize = 5;
+// The weather responses are a few KB, anything much larger is not expected
+const DEFAULT_MAX_BODY: usize = 64 * 1024;
+
+// Longer notes are cut off, in characters
+const MAX_NOTE_LEN: usize = 1024;
+// Flash messages expire after a day at most
+const MAX_FLASH_TTL_SECS: u64 = 24 * 60 * 60;
+
+// Size of the custom area an image posted to `/image` is drawn into
+pub const IMAGE_WIDTH: usize = 256;
+pub const IMAGE_HEIGHT: usize = 64;
+
 pub struct HttpClient {
     client: Client<EspHttpConnection>,
+    retries: u32,
+    max_body: usize,
 }
 
 impl HttpClient {
     pub fn new() -> Result<Self> {
+        Self::with_options(DEFAULT_TIMEOUT, DEFAULT_RETRIES)
+    }
+
+    /// Creates a client whose requests fail after `timeout` without data and
+    /// whose `get` is attempted up to `retries` more times on failure.
+    pub fn with_options(timeout: Duration, retries: u32) -> Result<Self> {
         let conn = EspHttpConnection::new(&esp_idf_svc::http::client::Configuration {
             use_global_ca_store: true,
             crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            timeout: Some(timeout),
             ..Default::default()
         })?;
         let client = Client::wrap(conn);
-        Ok(HttpClient { client })
+        Ok(HttpClient {
+            client,
+            retries,
+            max_body: DEFAULT_MAX_BODY,
+        })
+    }
+
+    /// Limits response bodies to `max_body` bytes, before and after
+    /// decompression. Larger responses fail with `ResponseTooLarge` instead
+    /// of exhausting the heap.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
     }
 
     pub fn get(&mut self, url: &str) -> Result<String> {
-        let request = self.client.get(url.as_ref())?;
+        let mut attempt = 0;
+        loop {
+            match self.try_get(url) {
+                Ok(result) => return Ok(result),
+                // Client errors such as a bad API key won't go away on retry
+                Err(WmError::HttpStatus(status)) if status < 500 && status != 429 => {
+                    return Err(WmError::HttpStatus(status));
+                }
+                // Neither would an oversized response
+                Err(WmError::ResponseTooLarge(limit)) => {
+                    return Err(WmError::ResponseTooLarge(limit));
+                }
+                Err(err) if attempt < self.retries => {
+                    attempt += 1;
+                    println!("GET {} failed: {}, retrying ({})", url, err, attempt);
+                    sleep(RETRY_BACKOFF * attempt);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn try_get(&mut self, url: &str) -> Result<String> {
+        let mut url = String::from(url);
+        let mut visited: Vec<String> = Vec::new();
+        loop {
+            let request = self.client.get(&url)?;
+            let mut response = request.submit()?;
+            let status = response.status();
+            if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+                return read_response(response, self.max_body);
+            }
+            let next = match response.header("Location") {
+                Some(location) => resolve_redirect(&url, location),
+                None => return Err(WmError::HttpStatus(status)),
+            };
+            // Drain the body so the connection can be reused
+            let mut buf = [0_u8; 256];
+            while Read::read(&mut response, &mut buf)? > 0 {}
+
+            visited.push(url);
+            if visited.len() > MAX_REDIRECTS || visited.contains(&next) {
+                println!("Too many redirects or redirect loop at {}", next);
+                return Err(WmError::HttpStatus(status));
+            }
+            url = next;
+        }
+    }
+
+    pub fn post(&mut self, url: &str, body: &[u8], content_type: &str) -> Result<String> {
+        let content_length = body.len().to_string();
+        let headers = [
+            ("Content-Type", content_type),
+            ("Content-Length", content_length.as_str()),
+        ];
+        let mut request = self.client.post(url.as_ref(), &headers)?;
+        request.write_all(body)?;
+        request.flush()?;
         let response = request.submit()?;
-        let status = response.status();
-        let gzip = response
-            .header("Content-Encoding")
-            .unwrap_or_default()
-            .contains(&"gzip");
-        match status {
-            200 => {
-                let mut buf = [0_u8; 1024];
-                let mut reader = response;
-                let mut result = Vec::new();
-                loop {
-                    if let Ok(size) = Read::read(&mut reader, &mut buf) {
-                        if size == 0 {
-                            break;
-                        }
-                        result.extend_from_slice(&buf[..size]);
-                    }
-                }
-                if gzip {
-                    let mut d = libflate::gzip::Decoder::new(result.as_slice()).unwrap();
-                    let mut result = String::new();
-                    d.read_to_string(&mut result).unwrap();
-                    return Ok(result);
-                } else {
-                    let result = String::from_utf8(result).unwrap();
-                    return Ok(result);
+        read_response(response, self.max_body)
+    }
+}
+
+impl HttpGet for HttpClient {
+    fn get(&mut self, url: &str) -> Result<String> {
+        HttpClient::get(self, url)
+    }
+}
+
+/// Turns the `Location` of a redirect into an absolute URL relative to the
+/// URL that was requested.
+fn resolve_redirect(base: &str, location: &str) -> String {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return String::from(location);
+    }
+    let (scheme, rest) = base.split_once("://").unwrap_or(("http", base));
+    if let Some(location) = location.strip_prefix("//") {
+        return format!("{}://{}", scheme, location);
+    }
+    let host = rest.split('/').next().unwrap_or(rest);
+    if location.starts_with('/') {
+        return format!("{}://{}{}", scheme, host, location);
+    }
+    let path = rest.split(['?', '#']).next().unwrap_or(rest);
+    let dir = match path.rfind('/') {
+        Some(idx) if idx >= host.len() => &path[..=idx],
+        _ => host,
+    };
+    if dir.ends_with('/') {
+        format!("{}://{}{}", scheme, dir, location)
+    } else {
+        format!("{}://{}/{}", scheme, dir, location)
+    }
+}
+
+/// Decodes a `deflate` body, which should be zlib wrapped but is sent as a
+/// raw deflate stream by some servers.
+fn inflate(data: &[u8], max_body: usize) -> Result<String> {
+    let zlib = libflate::zlib::Decoder::new(data)
+        .map_err(WmError::from)
+        .and_then(|decoder| decode(decoder, max_body));
+    match zlib {
+        Err(WmError::ResponseTooLarge(limit)) => Err(WmError::ResponseTooLarge(limit)),
+        Err(_) => decode(libflate::deflate::Decoder::new(data), max_body),
+        result => result,
+    }
+}
+
+/// Reads a decompressed body, failing once it grows past `max_body` so a
+/// small compressed response can't expand to fill the heap.
+fn decode<D: std::io::Read>(decoder: D, max_body: usize) -> Result<String> {
+    let mut result = Vec::new();
+    decoder.take(max_body as u64 + 1).read_to_end(&mut result)?;
+    if result.len() > max_body {
+        return Err(WmError::ResponseTooLarge(max_body));
+    }
+    String::from_utf8(result).map_err(|err| err.utf8_error().into())
+}
+
+fn read_response<R>(mut response: R, max_body: usize) -> Result<String>
+where
+    R: Read + Headers + Status,
+    WmError: From<R::Error>,
+{
+    let status = response.status();
+    let encoding = response
+        .header("Content-Encoding")
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let content_length = response
+        .header("Content-Length")
+        .and_then(|length| length.trim().parse::<usize>().ok());
+    match status {
+        200..=299 => {
+            // Fail before reading anything when the size is announced
+            if content_length.map_or(false, |length| length > max_body) {
+                return Err(WmError::ResponseTooLarge(max_body));
+            }
+            let mut buf = [0_u8; 1024];
+            let mut result = Vec::new();
+            loop {
+                // A timed out read surfaces as an error instead of spinning
+                let size = Read::read(&mut response, &mut buf)?;
+                if size == 0 {
+                    break;
                 }
+                if result.len() + size > max_body {
+                    return Err(WmError::ResponseTooLarge(max_body));
+                }
+                result.extend_from_slice(&buf[..size]);
             }
-            _ => {
-                return Ok(String::from(""));
+            match encoding.as_str() {
+                "gzip" | "x-gzip" => {
+                    decode(libflate::gzip::Decoder::new(result.as_slice())?, max_body)
+                }
+                "deflate" => inflate(&result, max_body),
+                _ => String::from_utf8(result).map_err(|err| err.utf8_error().into()),
             }
         }
+        400..=599 => Err(WmError::HttpStatus(status)),
+        _ => Ok(String::from("")),
     }
 }
 
@@ -69,15 +253,58 @@ pub struct SensorRecord {
     time: String,
     temp: f32,
     humidity: f32,
+    dew_point: f32,
+    heat_index: f32,
 }
 
 impl SensorRecord {
     fn new(datetime: OffsetDateTime, sensor: (f32, f32)) -> Self {
-        let time = format!("{:02}:{:02}", datetime.hour(), datetime.minute());
+        let time = datetime.format(&Rfc3339).unwrap_or_default();
         SensorRecord {
             time,
             temp: sensor.0,
             humidity: sensor.1,
+            dew_point: dew_point(sensor.0, sensor.1),
+            heat_index: heat_index(sensor.0, sensor.1),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Clone)]
+pub struct StatusReport {
+    free_heap: u32,
+    uptime: u64,
+    rssi: Option<i8>,
+    wifi_reconnects: u32,
+    weather_age: Option<u64>,
+    // UNIX time of the last weather update, once the clock is synchronized
+    weather_updated: Option<i64>,
+    // The same time as RFC 3339, for people reading the JSON
+    weather_updated_at: Option<String>,
+    // None while the indoor sensor is missing
+    temp: Option<f32>,
+    humidity: Option<f32>,
+}
+
+impl StatusReport {
+    /// `weather_age` is the number of seconds since the last successful
+    /// weather update, `None` if none happened since boot.
+    pub fn new(
+        rssi: Option<i8>,
+        wifi_reconnects: u32,
+        weather_age: Option<u64>,
+        weather_updated: Option<OffsetDateTime>,
+        sensor: Option<(f32, f32)>,
+    ) -> Self {
+        StatusReport {
+            rssi,
+            wifi_reconnects,
+            weather_age,
+            weather_updated: weather_updated.map(OffsetDateTime::unix_timestamp),
+            weather_updated_at: weather_updated.and_then(|time| time.format(&Rfc3339).ok()),
+            temp: sensor.map(|sensor| sensor.0),
+            humidity: sensor.map(|sensor| sensor.1),
+            ..Default::default()
         }
     }
 }
@@ -85,31 +312,58 @@ impl SensorRecord {
 pub struct HttpServer {
     server: EspHttpServer,
     note_content: Arc<Mutex<String>>,
+    // Shown instead of the note until it expires
+    flash: Arc<Mutex<Option<(String, Instant)>>>,
     refresh_flag: Arc<Mutex<bool>>,
-    sensor_data: Arc<Mutex<Vec<SensorRecord>>>,
+    sensor_data: Arc<Mutex<VecDeque<SensorRecord>>>,
+    weather_config: Arc<Mutex<Option<(String, String)>>>,
+    screen: Arc<Mutex<Option<Display>>>,
+    status: Arc<Mutex<StatusReport>>,
+    settings: Arc<Mutex<Option<Settings>>>,
+    pending_settings: Arc<Mutex<Option<Settings>>>,
+    factory_reset: Arc<Mutex<bool>>,
+    image: Arc<Mutex<Option<Vec<u8>>>>,
+    auth: Option<String>,
 }
 
 impl HttpServer {
     pub fn new() -> Result<Self> {
         let server = EspHttpServer::new(&esp_idf_svc::http::server::Configuration::default())?;
         let note_content = Arc::new(Mutex::new(String::from("")));
+        let flash = Arc::new(Mutex::new(None));
         let refresh_flag = Arc::new(Mutex::new(false));
-        let sensor_data = Arc::new(Mutex::new(Vec::new()));
+        let sensor_data = Arc::new(Mutex::new(VecDeque::new()));
+        let weather_config = Arc::new(Mutex::new(None));
+        let screen = Arc::new(Mutex::new(None));
+        let status = Arc::new(Mutex::new(StatusReport::default()));
+        let settings = Arc::new(Mutex::new(None));
+        let pending_settings = Arc::new(Mutex::new(None));
+        let factory_reset = Arc::new(Mutex::new(false));
+        let image = Arc::new(Mutex::new(None));
         Ok(HttpServer {
             server,
             note_content,
+            flash,
             refresh_flag,
             sensor_data,
+            weather_config,
+            screen,
+            status,
+            settings,
+            pending_settings,
+            factory_reset,
+            image,
+            auth: None,
         })
     }
 
     pub fn add_sensor_data(&mut self, datetime: OffsetDateTime, sensor: (f32, f32)) -> Result<()> {
         let mut sensor_data = self.sensor_data.lock().unwrap();
         let record = SensorRecord::new(datetime, sensor);
-        if sensor_data.len() >= 12 * 24 {
-            sensor_data.clear();
+        while sensor_data.len() >= SENSOR_HISTORY_LEN {
+            sensor_data.pop_front();
         }
-        sensor_data.push(record);
+        sensor_data.push_back(record);
         Ok(())
     }
 
@@ -118,6 +372,17 @@ impl HttpServer {
         Ok(note_content.clone())
     }
 
+    /// The message posted to `/flash`, or None once its TTL has passed.
+    pub fn get_flash_message(&mut self) -> Result<Option<String>> {
+        let mut flash = self.flash.lock().unwrap();
+        if let Some((_, expiry)) = flash.as_ref() {
+            if Instant::now() >= *expiry {
+                *flash = None;
+            }
+        }
+        Ok(flash.as_ref().map(|(message, _)| message.clone()))
+    }
+
     pub fn get_refresh_flag(&mut self) -> Result<bool> {
         let mut refresh_flag = self.refresh_flag.lock().unwrap();
         if *refresh_flag == true {
@@ -128,25 +393,77 @@ impl HttpServer {
         }
     }
 
+    /// Publishes the settings currently in effect for the `/settings` page.
+    pub fn set_settings(&mut self, settings: &Settings) -> Result<()> {
+        *self.settings.lock().unwrap() = Some(settings.clone());
+        Ok(())
+    }
+
+    /// Returns the settings submitted through `/settings` since the last call.
+    pub fn take_settings(&mut self) -> Result<Option<Settings>> {
+        Ok(self.pending_settings.lock().unwrap().take())
+    }
+
+    /// Returns true once after a factory reset was requested.
+    pub fn take_factory_reset(&mut self) -> Result<bool> {
+        let mut factory_reset = self.factory_reset.lock().unwrap();
+        Ok(std::mem::replace(&mut *factory_reset, false))
+    }
+
+    /// The 1-bit image posted to `/image`, packed MSB first with set bits
+    /// drawn black, or None to show the note instead.
+    pub fn get_image(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.image.lock().unwrap().clone())
+    }
+
+    /// Requires HTTP Basic auth on the endpoints that change state. Must be
+    /// called before `add_handlers`.
+    pub fn set_credentials(&mut self, username: &str, password: &str) {
+        let token = base64_encode(format!("{}:{}", username, password).as_bytes());
+        self.auth = Some(format!("Basic {}", token));
+    }
+
+    pub fn set_status(&mut self, report: StatusReport) -> Result<()> {
+        let mut status = self.status.lock().unwrap();
+        *status = report;
+        Ok(())
+    }
+
+    /// Keeps the last rendered frame so it can be served by `/screenshot`.
+    pub fn set_screen(&mut self, display: Display) -> Result<()> {
+        let mut screen = self.screen.lock().unwrap();
+        *screen = Some(display);
+        Ok(())
+    }
+
+    /// Returns the `(location, key)` pair posted to `/config` since the last call.
+    pub fn take_weather_config(&mut self) -> Result<Option<(String, String)>> {
+        let mut weather_config = self.weather_config.lock().unwrap();
+        Ok(weather_config.take())
+    }
+
     pub fn add_handlers(&mut self) -> Result<()> {
         let note_content = Arc::clone(&self.note_content);
         self.server.fn_handler("/", Method::Get, move |request| {
-            let template = include_str!("index.html");
-            let note_content = note_content.lock().unwrap().clone();
-            let html = template.replace("[[[PLACEHOLDER]]]", &note_content);
-            let mut response = request.into_ok_response()?;
+            let html = render_index(&note_content.lock().unwrap());
+            let mut response = request.into_response(200, None, &[HTML_CONTENT_TYPE])?;
             response.write_all(html.as_bytes())?;
             Ok(())
         })?;
 
         let refresh_flag = Arc::clone(&self.refresh_flag);
+        let auth = self.auth.clone();
         self.server
             .fn_handler("/refresh", Method::Get, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
                 let mut refresh_flag = refresh_flag.lock().unwrap();
                 *refresh_flag = true;
 
                 let html = include_str!("completed.html");
-                let mut response = request.into_ok_response()?;
+                let mut response = request.into_response(200, None, &[HTML_CONTENT_TYPE])?;
                 response.write_all(html.as_bytes())?;
                 Ok(())
             })?;
@@ -156,44 +473,702 @@ impl HttpServer {
             .fn_handler("/sensor", Method::Get, move |request| {
                 let sensor_data = sensor_data.lock().unwrap();
                 let json = serde_json::to_string(&*sensor_data).unwrap_or("".into());
-                let mut response = request.into_ok_response()?;
+                let mut response = request.into_response(200, None, &[JSON_CONTENT_TYPE])?;
                 response.write_all(json.as_bytes())?;
                 Ok(())
             })?;
 
+        let sensor_data = Arc::clone(&self.sensor_data);
+        self.server
+            .fn_handler("/sensor.csv", Method::Get, move |request| {
+                let sensor_data = sensor_data.lock().unwrap();
+                let mut csv = String::from("time,temp,humidity\n");
+                for record in sensor_data.iter() {
+                    csv += &format!(
+                        "{},{:.2},{:.2}\n",
+                        record.time, record.temp, record.humidity
+                    );
+                }
+                let mut response = request.into_response(
+                    200,
+                    None,
+                    &[
+                        ("Content-Type", "text/csv"),
+                        ("Content-Disposition", "attachment; filename=\"sensor.csv\""),
+                    ],
+                )?;
+                response.write_all(csv.as_bytes())?;
+                Ok(())
+            })?;
+
         self.server
             .fn_handler("/report", Method::Get, move |request| {
                 let html = include_str!("report.html");
-                let mut response = request.into_ok_response()?;
+                let mut response = request.into_response(200, None, &[HTML_CONTENT_TYPE])?;
                 response.write_all(html.as_bytes())?;
                 Ok(())
             })?;
 
         let note_content = Arc::clone(&self.note_content);
+        let auth = self.auth.clone();
         self.server.fn_handler("/", Method::Post, move |request| {
-            let mut buf = [0_u8; 1024];
+            if !is_authorized(&auth, request.header("Authorization")) {
+                request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                return Ok(());
+            }
             let mut reader = request;
-            let mut result = Vec::new();
-            loop {
-                if let Ok(size) = reader.read(&mut buf) {
-                    if size == 0 {
-                        break;
-                    }
-                    result.extend_from_slice(&buf[..size]);
+            let result = match read_body(&mut reader, DEFAULT_MAX_BODY) {
+                Ok(body) => body,
+                Err(err) => {
+                    let (status, message) = match err {
+                        WmError::ResponseTooLarge(_) => (413, "body too large"),
+                        _ => (400, "failed to read body"),
+                    };
+                    let mut response = reader.into_response(status, None, &[TEXT_CONTENT_TYPE])?;
+                    response.write_all(message.as_bytes())?;
+                    return Ok(());
                 }
-            }
+            };
 
-            let result = std::str::from_utf8(&result)?;
-            let result = result.trim_start_matches("sticky=").to_string();
+            let result = match std::str::from_utf8(&result) {
+                Ok(result) => result,
+                Err(_) => {
+                    let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                    response.write_all("body must be UTF-8".as_bytes())?;
+                    return Ok(());
+                }
+            };
+            let note = match parse_form(result).into_iter().find(|(k, _)| k == "sticky") {
+                Some((_, note)) => note.chars().take(MAX_NOTE_LEN).collect(),
+                None => {
+                    let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                    response.write_all("missing sticky field".as_bytes())?;
+                    return Ok(());
+                }
+            };
             let mut note_content = note_content.lock().unwrap();
-            *note_content = result;
+            *note_content = note;
 
             let html = include_str!("completed.html");
-            let mut response = reader.into_ok_response()?;
+            let mut response = reader.into_response(200, None, &[HTML_CONTENT_TYPE])?;
             response.write_all(html.as_bytes())?;
             Ok(())
         })?;
 
+        let flash = Arc::clone(&self.flash);
+        let refresh_flag = Arc::clone(&self.refresh_flag);
+        let auth = self.auth.clone();
+        self.server
+            .fn_handler("/flash", Method::Post, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
+                let mut reader = request;
+                let body = match read_body(&mut reader, DEFAULT_MAX_BODY) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        let (status, message) = match err {
+                            WmError::ResponseTooLarge(_) => (413, "body too large"),
+                            _ => (400, "failed to read body"),
+                        };
+                        let mut response =
+                            reader.into_response(status, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all(message.as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                let form = match std::str::from_utf8(&body) {
+                    Ok(body) => parse_form(body),
+                    Err(_) => {
+                        let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all("body must be UTF-8".as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                let field = |name: &str| {
+                    form.iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.clone())
+                };
+                let ttl = field("ttl").and_then(|ttl| ttl.trim().parse::<u64>().ok());
+                let (message, ttl) = match (field("message"), ttl) {
+                    (Some(message), Some(ttl)) if !message.is_empty() && ttl > 0 => (message, ttl),
+                    _ => {
+                        let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all("expected a message and a ttl in seconds".as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                let message = message.chars().take(MAX_NOTE_LEN).collect();
+                let expiry = Instant::now() + Duration::from_secs(ttl.min(MAX_FLASH_TTL_SECS));
+                *flash.lock().unwrap() = Some((message, expiry));
+                *refresh_flag.lock().unwrap() = true;
+                let html = include_str!("completed.html");
+                let mut response = reader.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                response.write_all(html.as_bytes())?;
+                Ok(())
+            })?;
+
+        let status = Arc::clone(&self.status);
+        self.server
+            .fn_handler("/status", Method::Get, move |request| {
+                let mut report = status.lock().unwrap().clone();
+                report.free_heap = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+                report.uptime = unsafe { esp_idf_sys::esp_timer_get_time() } as u64 / 1000000;
+                let json = serde_json::to_string(&report).unwrap_or("".into());
+                let mut response = request.into_response(200, None, &[JSON_CONTENT_TYPE])?;
+                response.write_all(json.as_bytes())?;
+                Ok(())
+            })?;
+
+        let status = Arc::clone(&self.status);
+        self.server
+            .fn_handler("/metrics", Method::Get, move |request| {
+                let report = status.lock().unwrap().clone();
+                let free_heap = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+                let text = render_metrics(&report, free_heap);
+                let mut response = request.into_response(
+                    200,
+                    None,
+                    &[("Content-Type", "text/plain; version=0.0.4")],
+                )?;
+                response.write_all(text.as_bytes())?;
+                Ok(())
+            })?;
+
+        self.server
+            .fn_handler("/logs", Method::Get, move |request| {
+                let mut response = request.into_response(200, None, &[TEXT_CONTENT_TYPE])?;
+                response.write_all(log::dump().as_bytes())?;
+                Ok(())
+            })?;
+
+        let screen = Arc::clone(&self.screen);
+        self.server
+            .fn_handler("/screenshot", Method::Get, move |request| {
+                let png = match screen.lock().unwrap().as_ref() {
+                    Some(display) => display.to_png()?,
+                    None => {
+                        request.into_status_response(404)?;
+                        return Ok(());
+                    }
+                };
+                let mut response =
+                    request.into_response(200, None, &[("Content-Type", "image/png")])?;
+                response.write_all(&png)?;
+                Ok(())
+            })?;
+
+        let weather_config = Arc::clone(&self.weather_config);
+        let refresh_flag = Arc::clone(&self.refresh_flag);
+        let auth = self.auth.clone();
+        self.server
+            .fn_handler("/config", Method::Post, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
+                let mut reader = request;
+                let body = match read_body(&mut reader, DEFAULT_MAX_BODY) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        let (status, message) = match err {
+                            WmError::ResponseTooLarge(_) => (413, "body too large"),
+                            _ => (400, "failed to read body"),
+                        };
+                        let mut response =
+                            reader.into_response(status, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all(message.as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                let body = match std::str::from_utf8(&body) {
+                    Ok(body) => body,
+                    Err(_) => {
+                        let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all("body must be UTF-8".as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                match parse_weather_config(body) {
+                    Ok(config) => {
+                        *weather_config.lock().unwrap() = Some(config);
+                        // Like `/settings`, apply it right away
+                        *refresh_flag.lock().unwrap() = true;
+                        let html = include_str!("completed.html");
+                        let mut response = reader.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                        response.write_all(html.as_bytes())?;
+                    }
+                    Err(_) => {
+                        let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all("location must not be empty".as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+        let settings = Arc::clone(&self.settings);
+        self.server
+            .fn_handler("/settings", Method::Get, move |request| {
+                let html = match settings.lock().unwrap().as_ref() {
+                    Some(settings) => render_settings(settings),
+                    None => {
+                        request.into_status_response(503)?;
+                        return Ok(());
+                    }
+                };
+                let mut response = request.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                response.write_all(html.as_bytes())?;
+                Ok(())
+            })?;
+
+        let auth = self.auth.clone();
+        let settings = Arc::clone(&self.settings);
+        let pending_settings = Arc::clone(&self.pending_settings);
+        let refresh_flag = Arc::clone(&self.refresh_flag);
+        self.server
+            .fn_handler("/settings", Method::Post, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
+                let mut reader = request;
+                let body = match read_body(&mut reader, DEFAULT_MAX_BODY) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        let (status, message) = match err {
+                            WmError::ResponseTooLarge(_) => (413, "body too large"),
+                            _ => (400, "failed to read body"),
+                        };
+                        let mut response =
+                            reader.into_response(status, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all(message.as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                let body = match std::str::from_utf8(&body) {
+                    Ok(body) => body,
+                    Err(_) => {
+                        let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all("body must be UTF-8".as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                let current = settings.lock().unwrap().clone();
+                match current
+                    .ok_or(WmError::InvalidArgument)
+                    .and_then(|current| parse_settings(&current, &parse_form(body)))
+                {
+                    Ok(updated) => {
+                        *settings.lock().unwrap() = Some(updated.clone());
+                        *pending_settings.lock().unwrap() = Some(updated);
+                        *refresh_flag.lock().unwrap() = true;
+                        let html = include_str!("completed.html");
+                        let mut response = reader.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                        response.write_all(html.as_bytes())?;
+                    }
+                    Err(_) => {
+                        let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all("invalid settings".as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+        let auth = self.auth.clone();
+        let factory_reset = Arc::clone(&self.factory_reset);
+        let refresh_flag = Arc::clone(&self.refresh_flag);
+        self.server
+            .fn_handler("/settings/reset", Method::Post, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
+                *factory_reset.lock().unwrap() = true;
+                *refresh_flag.lock().unwrap() = true;
+                let html = include_str!("completed.html");
+                let mut response = request.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                response.write_all(html.as_bytes())?;
+                Ok(())
+            })?;
+
+        let auth = self.auth.clone();
+        let image = Arc::clone(&self.image);
+        let refresh_flag = Arc::clone(&self.refresh_flag);
+        self.server
+            .fn_handler("/image", Method::Post, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
+                let mut reader = request;
+                let body = match read_body(&mut reader, DEFAULT_MAX_BODY) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        let (status, message) = match err {
+                            WmError::ResponseTooLarge(_) => (413, "body too large"),
+                            _ => (400, "failed to read body"),
+                        };
+                        let mut response =
+                            reader.into_response(status, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all(message.as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                // An empty body removes the image and brings the note back
+                let parsed = if body.is_empty() {
+                    Ok(None)
+                } else {
+                    parse_image(&body).map(Some)
+                };
+                match parsed {
+                    Ok(bitmap) => {
+                        *image.lock().unwrap() = bitmap;
+                        *refresh_flag.lock().unwrap() = true;
+                        let html = include_str!("completed.html");
+                        let mut response = reader.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                        response.write_all(html.as_bytes())?;
+                    }
+                    Err(_) => {
+                        let mut response = reader.into_response(400, None, &[TEXT_CONTENT_TYPE])?;
+                        let message = format!(
+                            "expected a {}x{} 1-bit BMP or {} bytes of raw bitmap",
+                            IMAGE_WIDTH,
+                            IMAGE_HEIGHT,
+                            IMAGE_WIDTH * IMAGE_HEIGHT / 8
+                        );
+                        response.write_all(message.as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+        let auth = self.auth.clone();
+        self.server
+            .fn_handler("/reboot", Method::Post, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
+                println!("Reboot requested over HTTP");
+                let html = include_str!("completed.html");
+                let mut response = request.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                response.write_all(html.as_bytes())?;
+                response.flush()?;
+                drop(response);
+                // Let the response reach the client before the reset
+                sleep(Duration::from_secs(1));
+                esp_idf_hal::reset::restart();
+            })?;
+
+        let auth = self.auth.clone();
+        self.server
+            .fn_handler("/ota", Method::Post, move |request| {
+                if !is_authorized(&auth, request.header("Authorization")) {
+                    request.into_response(401, None, &[UNAUTHORIZED_HEADER])?;
+                    return Ok(());
+                }
+                let mut reader = request;
+                match write_ota_image(&mut reader) {
+                    Ok(size) => {
+                        println!("OTA update of {} bytes completed, rebooting", size);
+                        let html = include_str!("completed.html");
+                        let mut response = reader.into_response(200, None, &[HTML_CONTENT_TYPE])?;
+                        response.write_all(html.as_bytes())?;
+                        response.flush()?;
+                        drop(response);
+                        sleep(Duration::from_secs(1));
+                        esp_idf_hal::reset::restart();
+                    }
+                    Err(err) => {
+                        println!("OTA update failed: {}", err);
+                        let mut response = reader.into_response(500, None, &[TEXT_CONTENT_TYPE])?;
+                        response.write_all(format!("OTA update failed: {}", err).as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
         Ok(())
     }
 }
+
+/// Formats the status as Prometheus text exposition. Metrics without a value,
+/// such as the RSSI while disconnected or a missing sensor, are left out.
+fn render_metrics(report: &StatusReport, free_heap: u32) -> String {
+    let metrics = [
+        (
+            "wm4esp_indoor_temp_celsius",
+            "Indoor temperature",
+            "gauge",
+            report.temp.map(|temp| format!("{:.2}", temp)),
+        ),
+        (
+            "wm4esp_indoor_humidity_percent",
+            "Indoor relative humidity",
+            "gauge",
+            report.humidity.map(|humidity| format!("{:.2}", humidity)),
+        ),
+        (
+            "wm4esp_free_heap_bytes",
+            "Free heap",
+            "gauge",
+            Some(free_heap.to_string()),
+        ),
+        (
+            "wm4esp_wifi_rssi_dbm",
+            "Signal strength of the Wi-Fi access point",
+            "gauge",
+            report.rssi.map(|rssi| rssi.to_string()),
+        ),
+        (
+            "wm4esp_wifi_reconnects_total",
+            "Wi-Fi reconnects since boot",
+            "counter",
+            Some(report.wifi_reconnects.to_string()),
+        ),
+        (
+            "wm4esp_weather_last_update_timestamp",
+            "UNIX time of the last successful weather update",
+            "gauge",
+            report.weather_updated.map(|time| time.to_string()),
+        ),
+    ];
+    let mut text = String::new();
+    for (name, help, kind, value) in metrics {
+        if let Some(value) = value {
+            text += &format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            );
+        }
+    }
+    text
+}
+
+/// Streams the request body into the next OTA slot. The image is validated
+/// when the update completes, which also selects it as the boot partition.
+fn write_ota_image<R: Read>(reader: &mut R) -> Result<usize> {
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut buf = [0_u8; 4096];
+    let mut total = 0;
+    loop {
+        let size = match reader.read(&mut buf) {
+            Ok(size) => size,
+            Err(_) => {
+                update.abort()?;
+                return Err(WmError::InternalError);
+            }
+        };
+        if size == 0 {
+            break;
+        }
+        if let Err(err) = update.write_all(&buf[..size]) {
+            update.abort()?;
+            return Err(err.into());
+        }
+        total += size;
+        if total % (64 * 1024) < size {
+            println!("OTA update: {} KB written", total / 1024);
+        }
+    }
+    update.complete()?;
+    Ok(total)
+}
+
+/// Accepts either a raw bitmap in the layout `Display::bitmap` expects or an
+/// uncompressed 1-bit BMP, both exactly IMAGE_WIDTH x IMAGE_HEIGHT. Returns the
+/// raw bitmap with set bits meaning black.
+fn parse_image(body: &[u8]) -> Result<Vec<u8>> {
+    if !body.starts_with(b"BM") {
+        if body.len() != IMAGE_WIDTH * IMAGE_HEIGHT / 8 {
+            return Err(WmError::InvalidArgument);
+        }
+        return Ok(body.to_vec());
+    }
+    let field = |offset: usize, len: usize| -> Result<u32> {
+        let bytes = offset
+            .checked_add(len)
+            .and_then(|end| body.get(offset..end))
+            .ok_or(WmError::InvalidArgument)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, byte| (value << 8) | *byte as u32))
+    };
+    let data_offset = field(10, 4)? as usize;
+    let header_size = field(14, 4)? as usize;
+    // Both come straight from the upload, so bound them before using them
+    if data_offset > body.len() || header_size > body.len() {
+        return Err(WmError::InvalidArgument);
+    }
+    let width = field(18, 4)? as i32;
+    let height = field(22, 4)? as i32;
+    if width as usize != IMAGE_WIDTH
+        || height.unsigned_abs() as usize != IMAGE_HEIGHT
+        || field(28, 2)? != 1
+        || field(30, 4)? != 0
+    {
+        return Err(WmError::InvalidArgument);
+    }
+    // Bits select a palette entry, so follow the palette rather than assuming
+    // that 1 is black
+    let palette = header_size
+        .checked_add(14)
+        .ok_or(WmError::InvalidArgument)?;
+    let luma = |entry: usize| -> Result<u32> {
+        let offset = entry
+            .checked_mul(4)
+            .and_then(|offset| offset.checked_add(palette))
+            .ok_or(WmError::InvalidArgument)?;
+        let bgr = offset
+            .checked_add(3)
+            .and_then(|end| body.get(offset..end))
+            .ok_or(WmError::InvalidArgument)?;
+        Ok(bgr.iter().map(|byte| *byte as u32).sum())
+    };
+    let invert = luma(0)? < luma(1)?;
+    // Rows are padded to four bytes and stored bottom-up unless height is negative
+    let stride = (IMAGE_WIDTH / 8 + 3) / 4 * 4;
+    let mut bitmap = Vec::with_capacity(IMAGE_WIDTH * IMAGE_HEIGHT / 8);
+    for row in 0..IMAGE_HEIGHT {
+        let src_row = if height > 0 {
+            IMAGE_HEIGHT - 1 - row
+        } else {
+            row
+        };
+        let start = src_row
+            .checked_mul(stride)
+            .and_then(|start| start.checked_add(data_offset))
+            .ok_or(WmError::InvalidArgument)?;
+        let data = start
+            .checked_add(IMAGE_WIDTH / 8)
+            .and_then(|end| body.get(start..end))
+            .ok_or(WmError::InvalidArgument)?;
+        bitmap.extend(data.iter().map(|byte| if invert { !byte } else { *byte }));
+    }
+    Ok(bitmap)
+}
+
+fn is_authorized(auth: &Option<String>, header: Option<&str>) -> bool {
+    match auth {
+        Some(expected) => header.map_or(false, |header| {
+            constant_time_eq(header.as_bytes(), expected.as_bytes())
+        }),
+        None => true,
+    }
+}
+
+/// Compares without stopping at the first mismatch, so the response time
+/// doesn't reveal how much of a guessed password was right. Only the length
+/// leaks.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let value = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                result.push(ALPHABET[((value >> (18 - 6 * idx)) & 0x3F) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+/// Reads a request body, giving up once it grows past `max_body` bytes so a
+/// client can't exhaust the heap. A failed read, e.g. a dropped connection,
+/// is an error too rather than the end of a truncated body.
+fn read_body<R: Read>(reader: &mut R, max_body: usize) -> Result<Vec<u8>> {
+    let mut buf = [0_u8; 1024];
+    let mut result = Vec::new();
+    loop {
+        let size = reader.read(&mut buf).map_err(|_| WmError::InternalError)?;
+        if size == 0 {
+            break;
+        }
+        if result.len() + size > max_body {
+            return Err(WmError::ResponseTooLarge(max_body));
+        }
+        result.extend_from_slice(&buf[..size]);
+    }
+    Ok(result)
+}
+
+/// Accepts either `{"location": "...", "key": "..."}` or an url-encoded form
+/// with the same field names. The key may be left out to keep the current one.
+fn parse_weather_config(body: &str) -> Result<(String, String)> {
+    let (location, key) = if body.trim_start().starts_with('{') {
+        let parsed: serde_json::Value = serde_json::from_str(body)?;
+        let field = |name: &str| parsed[name].as_str().unwrap_or_default().to_string();
+        (field("location"), field("key"))
+    } else {
+        let form = parse_form(body);
+        let field = |name: &str| {
+            form.iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default()
+        };
+        (field("location"), field("key"))
+    };
+    let location = location.trim().to_string();
+    if location.is_empty() {
+        return Err(WmError::InvalidArgument);
+    }
+    Ok((location, key.trim().to_string()))
+}
+
+fn render_settings(settings: &Settings) -> String {
+    include_str!("settings.html")
+        .replace("[[[LOCATION]]]", &escape_html(&settings.location))
+        .replace("[[[UTC_OFFSET]]]", &settings.utc_offset_hours.to_string())
+        .replace("[[[START_HOUR]]]", &settings.refresh_start_hour.to_string())
+        .replace("[[[END_HOUR]]]", &settings.refresh_end_hour.to_string())
+        .replace(
+            "[[[INTERVAL]]]",
+            &settings.refresh_interval_minutes.to_string(),
+        )
+}
+
+/// Applies the submitted form fields on top of `current`. Missing or empty
+/// fields, including the key, keep their current value.
+fn parse_settings(current: &Settings, form: &[(String, String)]) -> Result<Settings> {
+    let mut settings = current.clone();
+    for (name, value) in form {
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let invalid = |_| WmError::InvalidArgument;
+        match name.as_str() {
+            "location" => settings.location = value.to_string(),
+            "key" => settings.key = value.to_string(),
+            "utc_offset_hours" => settings.utc_offset_hours = value.parse().map_err(invalid)?,
+            "refresh_start_hour" => settings.refresh_start_hour = value.parse().map_err(invalid)?,
+            "refresh_end_hour" => settings.refresh_end_hour = value.parse().map_err(invalid)?,
+            "refresh_interval_minutes" => {
+                settings.refresh_interval_minutes = value.parse().map_err(invalid)?
+            }
+            _ => {}
+        }
+    }
+    settings.validate()?;
+    Ok(settings)
+}
@@ -1,2 +1,15 @@
+use crate::error::Result;
+
+pub mod form;
+#[cfg(feature = "esp")]
 pub mod http;
+#[cfg(feature = "esp")]
+pub mod mqtt;
+#[cfg(feature = "esp")]
 pub mod wifi;
+
+/// Fetches a URL as text. Implemented by `HttpClient`, and by anything
+/// serving canned responses so the parsing can run without a network.
+pub trait HttpGet {
+    fn get(&mut self, url: &str) -> Result<String>;
+}
@@ -0,0 +1,155 @@
+use crate::error::Result;
+
+use embedded_svc::mqtt::client::{Event, Publish, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration};
+use esp_idf_sys::{esp, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac};
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+pub struct MqttPublisher {
+    // Shared with the event handler, which announces on every connect. None
+    // only until `new` returns
+    client: Arc<Mutex<Option<EspMqttClient>>>,
+    topic: String,
+    device_id: String,
+    connected: Arc<AtomicBool>,
+    pending_announce: Arc<AtomicBool>,
+}
+
+/// Derives a stable identifier such as `wm4esp_a1b2c3d4e5f6` from the
+/// station MAC address.
+fn device_id() -> Result<String> {
+    let mut mac = [0u8; 6];
+    esp!(unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) })?;
+    let hex: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!("wm4esp_{}", hex))
+}
+
+impl MqttPublisher {
+    /// Connects to `mqtt://host:port`. The underlying ESP-IDF client keeps
+    /// reconnecting on its own whenever the broker goes away.
+    pub fn new(host: &str, port: u16, username: &str, password: &str, topic: &str) -> Result<Self> {
+        let url = format!("mqtt://{}:{}", host, port);
+        let device_id = device_id()?;
+        let conf = MqttClientConfiguration {
+            client_id: Some(&device_id),
+            username: if username.is_empty() {
+                None
+            } else {
+                Some(username)
+            },
+            password: if password.is_empty() {
+                None
+            } else {
+                Some(password)
+            },
+            ..Default::default()
+        };
+
+        let shared = Arc::new(Mutex::new(None));
+        let connected = Arc::new(AtomicBool::new(false));
+        let pending_announce = Arc::new(AtomicBool::new(false));
+        let state = Arc::clone(&connected);
+        let pending = Arc::clone(&pending_announce);
+        let handler_client = Arc::clone(&shared);
+        let handler_id = device_id.clone();
+        let handler_topic = topic.to_string();
+        let client = EspMqttClient::new(&url, &conf, move |event| match event {
+            Ok(Event::Connected(_)) => {
+                println!("MQTT connection established");
+                state.store(true, Ordering::SeqCst);
+                // Re-announce after every (re)connect in case the broker lost
+                // its retained messages. A client that is busy publishing (or
+                // not stored yet) is left alone, `publish_sensor` retries then
+                let announced = match handler_client.try_lock() {
+                    Ok(mut client) => match client.as_mut() {
+                        Some(client) => match announce(client, &handler_id, &handler_topic) {
+                            Ok(()) => true,
+                            Err(err) => {
+                                println!("Failed to publish MQTT discovery: {}", err);
+                                false
+                            }
+                        },
+                        None => false,
+                    },
+                    Err(_) => false,
+                };
+                pending.store(!announced, Ordering::SeqCst);
+            }
+            Ok(Event::Disconnected) => {
+                println!("MQTT connection lost");
+                state.store(false, Ordering::SeqCst);
+            }
+            _ => {}
+        })?;
+
+        *shared.lock().unwrap() = Some(client);
+        Ok(MqttPublisher {
+            client: shared,
+            topic: topic.into(),
+            device_id,
+            connected,
+            pending_announce,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Publishes the reading as JSON, silently skipping it while the broker
+    /// is unreachable so stale samples don't pile up in the outbox.
+    pub fn publish_sensor(&mut self, sensor: (f32, f32)) -> Result<()> {
+        if !self.is_connected() {
+            return Ok(());
+        }
+        let mut client = self.client.lock().unwrap();
+        let client = match client.as_mut() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        // Only left pending when the connect handler couldn't announce
+        if self.pending_announce.swap(false, Ordering::SeqCst) {
+            if let Err(err) = announce(client, &self.device_id, &self.topic) {
+                self.pending_announce.store(true, Ordering::SeqCst);
+                return Err(err);
+            }
+        }
+        let payload = json!({ "temp": sensor.0, "humidity": sensor.1 }).to_string();
+        client.publish(&self.topic, QoS::AtLeastOnce, false, payload.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Publishes retained Home Assistant discovery configs so the temperature and
+/// humidity sensors show up without any manual configuration.
+fn announce(client: &mut EspMqttClient, device_id: &str, state_topic: &str) -> Result<()> {
+    let entities = [
+        ("temperature", "Temperature", "°C", "temp"),
+        ("humidity", "Humidity", "%", "humidity"),
+    ];
+    for (class, name, unit, field) in entities {
+        let unique_id = format!("{}_{}", device_id, class);
+        let config = json!({
+            "name": name,
+            "unique_id": unique_id,
+            "device_class": class,
+            "unit_of_measurement": unit,
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} }}}}", field),
+            "device": {
+                "identifiers": [device_id],
+                "name": "wm4esp",
+                "manufacturer": "Espressif",
+                "model": "ESP32 weather display",
+            },
+        })
+        .to_string();
+        let topic = format!("{}/sensor/{}/{}/config", DISCOVERY_PREFIX, device_id, class);
+        client.publish(&topic, QoS::AtLeastOnce, true, config.as_bytes())?;
+    }
+    Ok(())
+}
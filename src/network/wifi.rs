@@ -1,16 +1,48 @@
-use crate::error::Result;
-use std::{thread::sleep, time::Duration};
+use crate::error::{Result, WmError};
+use crate::log;
+use crate::peripheral::led::{Led, LedMode};
+use std::net::Ipv4Addr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
 
+use embedded_svc::ipv4::{self, ClientSettings, Mask, Subnet};
 use embedded_svc::wifi::{ClientConfiguration, Configuration, Wifi};
 use esp_idf_hal::modem::Modem;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::sntp::{EspSntp, SyncStatus};
-use esp_idf_svc::wifi::EspWifi;
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+use esp_idf_svc::wifi::{EspWifi, WifiDriver};
+use esp_idf_sys::{esp, esp_wifi_sta_get_ap_info, wifi_ap_record_t};
+
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+const RECONNECT_BACKOFF_BASE: Duration = Duration::from_secs(10);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const HTTP_PORT: u16 = 80;
+
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub dns: Ipv4Addr,
+}
 
 pub struct WifiDevice<'a> {
     device: EspWifi<'a>,
     ntp: EspSntp,
+    ntp_timeout: Duration,
+    reconnect_count: u32,
+    // Set while a reconnect is in progress
+    reconnect_started: Option<Instant>,
+    reconnect_failures: u32,
+    next_reconnect: Option<Instant>,
+    ssid: String,
+    led: Option<Led>,
+    hostname: Option<String>,
+    mdns: Option<EspMdns>,
 }
 
 impl WifiDevice<'_> {
@@ -18,38 +50,247 @@ impl WifiDevice<'_> {
         modem: Modem,
         eventloop: EspSystemEventLoop,
         nvs: Option<EspDefaultNvsPartition>,
+        static_ip: Option<StaticIp>,
+        ntp_server: &str,
+        ntp_timeout: Duration,
     ) -> Result<Self> {
-        let device = EspWifi::new(modem, eventloop, nvs)?;
-        let ntp = EspSntp::new_default()?;
-        Ok(WifiDevice { device, ntp })
+        let device = match static_ip {
+            None => EspWifi::new(modem, eventloop, nvs)?,
+            Some(static_ip) => {
+                let driver = WifiDriver::new(modem, eventloop, nvs)?;
+                let sta_netif = EspNetif::new_with_conf(&NetifConfiguration {
+                    ip_configuration: ipv4::Configuration::Client(
+                        ipv4::ClientConfiguration::Fixed(ClientSettings {
+                            ip: static_ip.ip,
+                            subnet: Subnet {
+                                gateway: static_ip.gateway,
+                                mask: Mask(u32::from(static_ip.netmask).count_ones() as u8),
+                            },
+                            dns: Some(static_ip.dns),
+                            secondary_dns: None,
+                        }),
+                    ),
+                    ..NetifConfiguration::wifi_default_client()
+                })?;
+                let ap_netif = EspNetif::new_with_conf(&NetifConfiguration::wifi_default_router())?;
+                EspWifi::wrap_all(driver, sta_netif, ap_netif)?
+            }
+        };
+        // An empty server keeps the default pool.ntp.org servers
+        let ntp = if ntp_server.is_empty() {
+            EspSntp::new_default()?
+        } else {
+            let mut conf = SntpConf::default();
+            conf.servers[0] = ntp_server;
+            EspSntp::new(&conf)?
+        };
+        Ok(WifiDevice {
+            device,
+            ntp,
+            ntp_timeout,
+            reconnect_count: 0,
+            reconnect_started: None,
+            reconnect_failures: 0,
+            next_reconnect: None,
+            ssid: String::new(),
+            led: None,
+            hostname: None,
+            mdns: None,
+        })
+    }
+
+    /// Makes the device reachable as `<hostname>.local` once connected.
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        if !hostname.is_empty() {
+            self.hostname = Some(hostname.into());
+        }
+        self
+    }
+
+    /// Blinks `led` slowly while connecting and keeps it lit once connected.
+    pub fn with_led(mut self, led: Option<Led>) -> Self {
+        self.led = led;
+        self
+    }
+
+    fn set_led(&self, mode: LedMode) {
+        if let Some(led) = &self.led {
+            led.set_mode(mode);
+        }
     }
 
-    pub fn connect(&mut self, ssid: &str, password: &str) -> Result<()> {
+    /// Tries each `(ssid, password)` pair in order, skipping networks that are
+    /// not visible in a scan, and returns the SSID that connected.
+    pub fn connect_any(&mut self, networks: &[(&str, &str)]) -> Result<String> {
+        self.device
+            .set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        self.device.start()?;
+        self.set_led(LedMode::SlowBlink);
+        // If the scan itself fails, try every network blindly
+        let visible = self.device.scan().ok();
+
+        for (ssid, password) in networks.iter() {
+            if let Some(visible) = &visible {
+                if !visible.iter().any(|ap| ap.ssid.as_str() == *ssid) {
+                    log::push(format!("Wi-Fi network {} not found", ssid));
+                    continue;
+                }
+            }
+            match self.try_connect(ssid, password) {
+                Ok(()) => {
+                    log::push(format!("Wi-Fi connection established with {}", ssid));
+                    self.ssid = String::from(*ssid);
+                    self.set_led(LedMode::On);
+                    self.register_mdns();
+                    if let Err(err) = self.wait_for_sntp() {
+                        log::push(format!(
+                            "Clock not synchronized yet, retrying in the background: {}",
+                            err
+                        ));
+                    }
+                    return Ok(self.ssid.clone());
+                }
+                Err(err) => {
+                    log::push(format!("Wi-Fi connection to {} failed: {}", ssid, err));
+                    let _ = self.device.disconnect();
+                }
+            }
+        }
+        Err(WmError::InvalidArgument)
+    }
+
+    fn try_connect(&mut self, ssid: &str, password: &str) -> Result<()> {
         self.device
             .set_configuration(&Configuration::Client(ClientConfiguration {
                 ssid: ssid.into(),
                 password: password.into(),
                 ..Default::default()
             }))?;
-        self.device.start()?;
         self.device.connect()?;
+        let start = Instant::now();
         while !self.device.is_connected()? {
+            if start.elapsed() >= CONNECT_TIMEOUT {
+                return Err(WmError::InternalError);
+            }
             sleep(Duration::from_millis(500));
         }
-        println!("Wi-Fi connection established");
+        Ok(())
+    }
 
-        for _ in 0..20 {
-            if self.ntp.get_sync_status() == SyncStatus::Completed {
-                println!("NTP Server started");
-                break;
-            } else {
-                sleep(Duration::from_millis(500));
+    /// Reconnects with the last configuration if the link has dropped.
+    /// Called on every cycle of the main loop, so it never waits for the
+    /// connection: an attempt is started and checked on the following calls,
+    /// and attempts that time out after RECONNECT_TIMEOUT back off.
+    pub fn ensure_connected(&mut self) -> Result<()> {
+        if self.device.is_connected()? {
+            if self.reconnect_started.take().is_some() {
+                self.reconnect_count += 1;
+                self.reconnect_failures = 0;
+                self.next_reconnect = None;
+                log::push(format!(
+                    "Wi-Fi connection re-established (#{})",
+                    self.reconnect_count
+                ));
+                self.set_led(LedMode::On);
+                self.register_mdns();
+            }
+            return Ok(());
+        }
+        match self.reconnect_started {
+            Some(started) if started.elapsed() >= RECONNECT_TIMEOUT => {
+                // Back off 10, 20, 40, ... seconds, capped at RECONNECT_BACKOFF_MAX
+                let delay = RECONNECT_BACKOFF_BASE
+                    .saturating_mul(1 << self.reconnect_failures.min(5))
+                    .min(RECONNECT_BACKOFF_MAX);
+                self.reconnect_failures += 1;
+                self.reconnect_started = None;
+                self.next_reconnect = Some(Instant::now() + delay);
+                log::push(format!(
+                    "Wi-Fi reconnect failed {} time(s), next attempt in {}s",
+                    self.reconnect_failures,
+                    delay.as_secs()
+                ));
+                Ok(())
+            }
+            Some(_) => Ok(()),
+            None => {
+                let due = self
+                    .next_reconnect
+                    .map_or(true, |next_reconnect| Instant::now() >= next_reconnect);
+                if due {
+                    if self.reconnect_failures == 0 {
+                        log::push("Wi-Fi connection lost, reconnecting");
+                    }
+                    self.set_led(LedMode::SlowBlink);
+                    self.reconnect_started = Some(Instant::now());
+                    self.device.connect()?;
+                }
+                Ok(())
             }
         }
+    }
 
+    /// (Re)starts the mDNS responder and advertises the HTTP server. Failures
+    /// are only logged as the device stays reachable by IP.
+    fn register_mdns(&mut self) {
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname.clone(),
+            None => return,
+        };
+        // Dropping the old responder frees it so the new one starts clean
+        self.mdns = None;
+        let result = EspMdns::take().and_then(|mut mdns| {
+            mdns.set_hostname(&hostname)?;
+            mdns.set_instance_name(&hostname)?;
+            mdns.add_service(None, "_http", "_tcp", HTTP_PORT, &[])?;
+            Ok(mdns)
+        });
+        match result {
+            Ok(mdns) => {
+                println!("mDNS responder started as {}.local", hostname);
+                self.mdns = Some(mdns);
+            }
+            Err(err) => println!("Failed to start mDNS responder: {}", err),
+        }
+    }
+
+    /// Waits up to the configured timeout for the first SNTP sync and fails
+    /// if the clock is still unset afterwards.
+    fn wait_for_sntp(&self) -> Result<()> {
+        let start = Instant::now();
+        while !self.time_synced() {
+            if start.elapsed() >= self.ntp_timeout {
+                return Err(WmError::TimeSyncTimeout);
+            }
+            sleep(Duration::from_millis(500));
+        }
+        println!("NTP Server started");
         Ok(())
     }
 
+    /// True once SNTP has set the clock. The completed status is only reported
+    /// once by ESP-IDF, so a clock past 2020 counts as synchronized too.
+    pub fn time_synced(&self) -> bool {
+        self.ntp.get_sync_status() == SyncStatus::Completed
+            || OffsetDateTime::now_utc().year() > 2020
+    }
+
+    pub fn ssid(&self) -> &str {
+        &self.ssid
+    }
+
+    /// Times the connection was lost and re-established since boot.
+    pub fn reconnect_count(&self) -> u32 {
+        self.reconnect_count
+    }
+
+    /// Signal strength of the connected AP in dBm, fails when not connected.
+    pub fn rssi(&self) -> Result<i8> {
+        let mut info = wifi_ap_record_t::default();
+        esp!(unsafe { esp_wifi_sta_get_ap_info(&mut info) })?;
+        Ok(info.rssi)
+    }
+
     pub fn ip_addr(&self) -> Result<String> {
         let result = self.device.sta_netif().get_ip_info()?.ip;
         Ok(result.to_string())
@@ -0,0 +1,43 @@
+use crate::error::Result;
+
+use esp_idf_hal::adc::{self, AdcChannelDriver, AdcDriver, Atten11dB, ADC1};
+use esp_idf_hal::gpio::ADCPin;
+use esp_idf_hal::peripheral::Peripheral;
+
+// The cell is measured through a 100k/100k divider
+const VOLTAGE_DIVIDER: f32 = 2.0;
+// Rough LiPo discharge range used for the percentage
+const EMPTY_VOLTAGE: f32 = 3.3;
+const FULL_VOLTAGE: f32 = 4.2;
+
+pub struct Battery<'a> {
+    // The channel type depends on the pin, so the driver pair is kept behind
+    // a closure to let the pin be chosen at runtime
+    read_millivolts: Box<dyn FnMut() -> Result<u16> + 'a>,
+}
+
+impl<'a> Battery<'a> {
+    pub fn new<P: ADCPin<Adc = ADC1>>(
+        adc: impl Peripheral<P = ADC1> + 'a,
+        pin: impl Peripheral<P = P> + 'a,
+    ) -> Result<Self> {
+        let mut driver = AdcDriver::new(adc, &adc::config::Config::new().calibration(true))?;
+        let mut channel: AdcChannelDriver<'a, P, Atten11dB<ADC1>> = AdcChannelDriver::new(pin)?;
+        Ok(Battery {
+            read_millivolts: Box::new(move || Ok(driver.read(&mut channel)?)),
+        })
+    }
+
+    /// Returns the cell voltage, compensating for the divider.
+    pub fn read_voltage(&mut self) -> Result<f32> {
+        let millivolts = (self.read_millivolts)()?;
+        Ok(millivolts as f32 / 1000.0 * VOLTAGE_DIVIDER)
+    }
+
+    /// Returns the remaining charge in percent, assuming a linear discharge.
+    pub fn read_percentage(&mut self) -> Result<u8> {
+        let voltage = self.read_voltage()?;
+        let ratio = (voltage - EMPTY_VOLTAGE) / (FULL_VOLTAGE - EMPTY_VOLTAGE);
+        Ok((ratio.clamp(0.0, 1.0) * 100.0).round() as u8)
+    }
+}
@@ -0,0 +1,198 @@
+use super::sensor::{check_presence, IndoorSensor, Reading};
+use crate::error::{Result, WmError};
+
+use esp_idf_hal::gpio::{InputPin, OutputPin};
+use esp_idf_hal::i2c::I2c;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::{i2c, units};
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const REQUEST_TIMEOUT: u32 = 10;
+// A forced measurement with 1x oversampling takes under 10ms
+const MEASURE_TIMEOUT: Duration = Duration::from_millis(100);
+const CHIP_ID: u8 = 0x60;
+
+const REG_CALIB_00: u8 = 0x88;
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_CALIB_26: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_DATA: u8 = 0xF7;
+
+// 1x oversampling of humidity, temperature and pressure in forced mode
+const CTRL_HUM: u8 = 0b001;
+const CTRL_MEAS: u8 = 0b001_001_01;
+
+/// Factory trimming values, named as in the datasheet.
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p1: f64,
+    p2: f64,
+    p3: f64,
+    p4: f64,
+    p5: f64,
+    p6: f64,
+    p7: f64,
+    p8: f64,
+    p9: f64,
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+pub struct Bme280<'a> {
+    device: i2c::I2cDriver<'a>,
+    address: u8,
+    // Read on first use, so a sensor attached later is still picked up
+    calibration: Option<Calibration>,
+}
+
+impl<'a> Bme280<'a> {
+    /// The sensor answers on 0x76, or 0x77 with SDO pulled high.
+    pub fn new<I2C: I2c>(
+        i2c: impl Peripheral<P = I2C> + 'a,
+        sda: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
+        scl: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
+        address: u8,
+        baud_rate: units::Hertz,
+    ) -> Result<Self> {
+        let config = i2c::config::Config::new()
+            .baudrate(baud_rate)
+            .scl_enable_pullup(true)
+            .sda_enable_pullup(true);
+        let device = i2c::I2cDriver::new(i2c, sda, scl, &config)?;
+        Ok(Bme280 {
+            device,
+            address,
+            calibration: None,
+        })
+    }
+
+    /// Checks the chip id and reads the factory trimming values.
+    fn calibrate(&mut self) -> Result<Calibration> {
+        let mut chip_id = [0u8; 1];
+        self.device
+            .write_read(self.address, &[REG_CHIP_ID], &mut chip_id, REQUEST_TIMEOUT)?;
+        if chip_id[0] != CHIP_ID {
+            return Err(WmError::InvalidArgument);
+        }
+        let mut low = [0u8; 26];
+        self.device
+            .write_read(self.address, &[REG_CALIB_00], &mut low, REQUEST_TIMEOUT)?;
+        let mut high = [0u8; 7];
+        self.device
+            .write_read(self.address, &[REG_CALIB_26], &mut high, REQUEST_TIMEOUT)?;
+
+        let unsigned =
+            |bytes: &[u8], idx: usize| u16::from_le_bytes([bytes[idx], bytes[idx + 1]]) as f64;
+        let signed =
+            |bytes: &[u8], idx: usize| i16::from_le_bytes([bytes[idx], bytes[idx + 1]]) as f64;
+        Ok(Calibration {
+            t1: unsigned(&low, 0),
+            t2: signed(&low, 2),
+            t3: signed(&low, 4),
+            p1: unsigned(&low, 6),
+            p2: signed(&low, 8),
+            p3: signed(&low, 10),
+            p4: signed(&low, 12),
+            p5: signed(&low, 14),
+            p6: signed(&low, 16),
+            p7: signed(&low, 18),
+            p8: signed(&low, 20),
+            p9: signed(&low, 22),
+            h1: low[25] as f64,
+            h2: signed(&high, 0),
+            h3: high[2] as f64,
+            // H4 and H5 are 12-bit values sharing the nibbles of 0xE5
+            h4: (((high[3] as i8 as i16) << 4) | (high[4] & 0x0F) as i16) as f64,
+            h5: (((high[5] as i8 as i16) << 4) | (high[4] >> 4) as i16) as f64,
+            h6: high[6] as i8 as f64,
+        })
+    }
+
+    /// Triggers a forced measurement and returns the raw pressure,
+    /// temperature and humidity readings.
+    fn measure(&mut self) -> Result<(f64, f64, f64)> {
+        self.device
+            .write(self.address, &[REG_CTRL_HUM, CTRL_HUM], REQUEST_TIMEOUT)?;
+        self.device
+            .write(self.address, &[REG_CTRL_MEAS, CTRL_MEAS], REQUEST_TIMEOUT)?;
+        let start = Instant::now();
+        loop {
+            let mut status = [0u8; 1];
+            self.device
+                .write_read(self.address, &[REG_STATUS], &mut status, REQUEST_TIMEOUT)?;
+            if status[0] & 0x08 == 0 {
+                break;
+            }
+            if start.elapsed() >= MEASURE_TIMEOUT {
+                return Err(WmError::InternalError);
+            }
+            sleep(Duration::from_millis(2));
+        }
+        let mut data = [0u8; 8];
+        self.device
+            .write_read(self.address, &[REG_DATA], &mut data, REQUEST_TIMEOUT)?;
+        let raw20 = |idx: usize| {
+            (((data[idx] as u32) << 12)
+                | ((data[idx + 1] as u32) << 4)
+                | ((data[idx + 2] as u32) >> 4)) as f64
+        };
+        let humidity = u16::from_be_bytes([data[6], data[7]]) as f64;
+        Ok((raw20(0), raw20(3), humidity))
+    }
+}
+
+impl IndoorSensor for Bme280<'_> {
+    /// Applies the floating point compensation formulas of the datasheet.
+    fn read(&mut self) -> Result<Reading> {
+        if self.calibration.is_none() {
+            self.calibration = Some(self.calibrate().map_err(check_presence)?);
+        }
+        let (adc_p, adc_t, adc_h) = self.measure().map_err(check_presence)?;
+        let c = self.calibration.as_ref().ok_or(WmError::InternalError)?;
+
+        let var1 = (adc_t / 16384.0 - c.t1 / 1024.0) * c.t2;
+        let var2 = (adc_t / 131072.0 - c.t1 / 8192.0).powi(2) * c.t3;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * c.p6 / 32768.0;
+        var2 += var1 * c.p5 * 2.0;
+        var2 = var2 / 4.0 + c.p4 * 65536.0;
+        var1 = (c.p3 * var1 * var1 / 524288.0 + c.p2 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * c.p1;
+        // Avoids a division by zero on an unprogrammed chip
+        let pressure = if var1 == 0.0 {
+            None
+        } else {
+            let mut pressure = 1048576.0 - adc_p;
+            pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+            let var1 = c.p9 * pressure * pressure / 2147483648.0;
+            let var2 = pressure * c.p8 / 32768.0;
+            pressure += (var1 + var2 + c.p7) / 16.0;
+            Some((pressure / 100.0) as f32)
+        };
+
+        let mut humidity = t_fine - 76800.0;
+        humidity = (adc_h - (c.h4 * 64.0 + c.h5 / 16384.0 * humidity))
+            * (c.h2 / 65536.0
+                * (1.0 + c.h6 / 67108864.0 * humidity * (1.0 + c.h3 / 67108864.0 * humidity)));
+        humidity *= 1.0 - c.h1 * humidity / 524288.0;
+
+        Ok(Reading {
+            temperature: temperature as f32,
+            humidity: humidity.clamp(0.0, 100.0) as f32,
+            pressure,
+        })
+    }
+}
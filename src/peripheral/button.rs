@@ -0,0 +1,55 @@
+use crate::error::Result;
+
+use esp_idf_hal::gpio::{AnyInputPin, PinDriver, Pull};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// The level must be stable this long before a press counts
+const DEBOUNCE: Duration = Duration::from_millis(50);
+const POLL_STACK_SIZE: usize = 2048;
+
+/// Push button wired between the pin and ground. The pin is polled from a
+/// background thread so presses are not missed while the app loop sleeps.
+pub struct Button {
+    pressed: Arc<AtomicBool>,
+}
+
+impl Button {
+    pub fn new(pin: AnyInputPin) -> Result<Self> {
+        let mut driver = PinDriver::input(pin)?;
+        driver.set_pull(Pull::Up)?;
+
+        let pressed = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&pressed);
+        thread::Builder::new()
+            .stack_size(POLL_STACK_SIZE)
+            .spawn(move || {
+                let mut stable = driver.is_low();
+                let mut last = stable;
+                let mut last_change = Instant::now();
+                loop {
+                    let level = driver.is_low();
+                    if level != last {
+                        last = level;
+                        last_change = Instant::now();
+                    } else if level != stable && last_change.elapsed() >= DEBOUNCE {
+                        stable = level;
+                        if stable {
+                            flag.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    sleep(POLL_INTERVAL);
+                }
+            })?;
+        Ok(Button { pressed })
+    }
+
+    /// Returns true once for every press since the last call.
+    pub fn take_press(&self) -> bool {
+        self.pressed.swap(false, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,29 @@
+use crate::display::Display;
+use crate::error::{Result, WmError};
+
+use embedded_graphics::primitives::Rectangle;
+
+/// A panel frames are pushed to. Only a full `draw` is required, the
+/// capability queries default to what every panel can do so callers can
+/// decide between refresh modes without knowing the driver.
+pub trait Device {
+    /// Shows `screen`, using the faster waveform if `fast` is set and the
+    /// panel supports it.
+    fn draw(&mut self, screen: &Display, fast: bool) -> Result<()>;
+
+    /// True if `draw` with `fast` set is quicker than a full refresh.
+    fn supports_fast(&self) -> bool {
+        false
+    }
+
+    /// True if `draw_partial` can refresh a region on its own.
+    fn supports_partial(&self) -> bool {
+        false
+    }
+
+    /// Refreshes only `region` of `screen`. Fails on panels without partial
+    /// refresh, see `supports_partial`.
+    fn draw_partial(&mut self, _screen: &Display, _region: Rectangle) -> Result<()> {
+        Err(WmError::InvalidArgument)
+    }
+}
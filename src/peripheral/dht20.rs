@@ -1,3 +1,4 @@
+use super::sensor::{check_presence, IndoorSensor, Reading};
 use crate::error::{Result, WmError};
 
 use esp_idf_hal::gpio::{InputPin, OutputPin};
@@ -6,41 +7,53 @@ use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_hal::{i2c, units};
 
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-const DEFAULT_BAUD_RATE: units::Hertz = units::Hertz(1000000);
-const I2C_ADDRESS: u8 = 0x38;
+// Timeout of a single I2C transaction in RTOS ticks. It does not bound the
+// polling loop while a measurement is in progress, see MEASURE_TIMEOUT.
 const REQUEST_TIMEOUT: u32 = 10;
+// The datasheet specifies ~80ms per conversion
+const MEASURE_TIMEOUT: Duration = Duration::from_millis(500);
+const READ_ATTEMPTS: usize = 3;
 
 pub struct DHT20<'a> {
     device: i2c::I2cDriver<'a>,
+    address: u8,
 }
 
 impl<'a> DHT20<'a> {
+    /// The sensor answers on 0x38 unless strapped otherwise. Run the bus at
+    /// 400kHz or less when it is shared with other devices.
     pub fn new<I2C: I2c>(
         i2c: impl Peripheral<P = I2C> + 'a,
         sda: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
         scl: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
+        address: u8,
+        baud_rate: units::Hertz,
     ) -> Result<Self> {
         let config = i2c::config::Config::new()
-            .baudrate(DEFAULT_BAUD_RATE)
+            .baudrate(baud_rate)
             .scl_enable_pullup(true)
             .sda_enable_pullup(true);
         let device = i2c::I2cDriver::new(i2c, sda, scl, &config)?;
-        Ok(DHT20 { device })
+        Ok(DHT20 { device, address })
     }
 
-    pub fn read(&mut self) -> Result<(f32, f32)> {
+    fn measure(&mut self) -> Result<(f32, f32)> {
         self.reset_sensor()?;
         let bytes: [u8; 3] = [0xAC, 0x33, 0x00];
-        self.device.write(I2C_ADDRESS, &bytes, REQUEST_TIMEOUT)?;
+        self.device.write(self.address, &bytes, REQUEST_TIMEOUT)?;
+        let start = Instant::now();
         while self.is_measuring()? {
+            if start.elapsed() >= MEASURE_TIMEOUT {
+                return Err(WmError::InternalError);
+            }
             sleep(Duration::from_millis(10));
         }
         let mut buffer = Vec::new();
         buffer.resize(7, 0);
         self.device
-            .read(I2C_ADDRESS, &mut buffer, REQUEST_TIMEOUT)?;
+            .read(self.address, &mut buffer, REQUEST_TIMEOUT)?;
         let mut raw: u32 = buffer[1] as u32;
         raw <<= 8;
         raw += buffer[2] as u32;
@@ -66,7 +79,7 @@ impl<'a> DHT20<'a> {
         let mut buffer = Vec::new();
         buffer.resize(1, 0);
         self.device
-            .read(I2C_ADDRESS, &mut buffer, REQUEST_TIMEOUT)?;
+            .read(self.address, &mut buffer, REQUEST_TIMEOUT)?;
         Ok(buffer[0])
     }
 
@@ -96,13 +109,36 @@ impl<'a> DHT20<'a> {
         let mut buffer = Vec::new();
         buffer.resize(3, 0);
         self.device
-            .write_read(I2C_ADDRESS, &bytes, &mut buffer, REQUEST_TIMEOUT)?;
+            .write_read(self.address, &bytes, &mut buffer, REQUEST_TIMEOUT)?;
         let bytes: [u8; 3] = [0xB0 | reg, buffer[1], buffer[2]];
-        self.device.write(I2C_ADDRESS, &bytes, REQUEST_TIMEOUT)?;
+        self.device.write(self.address, &bytes, REQUEST_TIMEOUT)?;
         Ok(())
     }
 }
 
+impl IndoorSensor for DHT20<'_> {
+    fn read(&mut self) -> Result<Reading> {
+        let mut attempt = 1;
+        let (temperature, humidity) = loop {
+            match self.measure().map_err(check_presence) {
+                Ok(values) => break values,
+                // `measure` reports a failed CRC or a conversion that never
+                // finished this way, the only errors worth another try
+                Err(WmError::InternalError) if attempt < READ_ATTEMPTS => {
+                    attempt += 1;
+                    sleep(Duration::from_millis(20));
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        Ok(Reading {
+            temperature,
+            humidity,
+            pressure: None,
+        })
+    }
+}
+
 fn crc_check(buffer: &[u8]) -> bool {
     let mut crc: u8 = 0xFF;
     for idx in 0..buffer.len() - 1 {
@@ -0,0 +1,78 @@
+use crate::error::Result;
+
+use esp_idf_hal::gpio::{AnyOutputPin, PinDriver};
+
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+const IDLE_INTERVAL: Duration = Duration::from_millis(50);
+const SLOW_BLINK_INTERVAL: Duration = Duration::from_millis(500);
+const FAST_BLINK_INTERVAL: Duration = Duration::from_millis(100);
+const BLINK_STACK_SIZE: usize = 2048;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LedMode {
+    Off,
+    On,
+    SlowBlink,
+    FastBlink,
+}
+
+/// Status LED driven from a background thread so it keeps blinking while
+/// the caller blocks. Clones share the same LED.
+#[derive(Clone)]
+pub struct Led {
+    mode: Arc<Mutex<LedMode>>,
+}
+
+impl Led {
+    pub fn new(pin: AnyOutputPin) -> Result<Self> {
+        let mut driver = PinDriver::output(pin)?;
+        driver.set_low()?;
+
+        let mode = Arc::new(Mutex::new(LedMode::Off));
+        let state = Arc::clone(&mode);
+        thread::Builder::new()
+            .stack_size(BLINK_STACK_SIZE)
+            .spawn(move || {
+                let mut lit = false;
+                loop {
+                    let mode = *state.lock().unwrap();
+                    let (level, interval) = match mode {
+                        LedMode::Off => (false, IDLE_INTERVAL),
+                        LedMode::On => (true, IDLE_INTERVAL),
+                        LedMode::SlowBlink => (!lit, SLOW_BLINK_INTERVAL),
+                        LedMode::FastBlink => (!lit, FAST_BLINK_INTERVAL),
+                    };
+                    if level != lit {
+                        let _ = if level {
+                            driver.set_high()
+                        } else {
+                            driver.set_low()
+                        };
+                        lit = level;
+                    }
+                    sleep(interval);
+                }
+            })?;
+        Ok(Led { mode })
+    }
+
+    pub fn mode(&self) -> LedMode {
+        *self.mode.lock().unwrap()
+    }
+
+    pub fn set_mode(&self, mode: LedMode) {
+        *self.mode.lock().unwrap() = mode;
+    }
+
+    /// Blinks quickly while `f` runs, then restores the previous mode.
+    pub fn flash_while<T>(&self, f: impl FnOnce() -> T) -> T {
+        let previous = self.mode();
+        self.set_mode(LedMode::FastBlink);
+        let result = f();
+        self.set_mode(previous);
+        result
+    }
+}
@@ -1,2 +1,16 @@
+#[cfg(feature = "esp")]
+pub mod battery;
+#[cfg(feature = "esp")]
+pub mod bme280;
+#[cfg(feature = "esp")]
+pub mod button;
+pub mod device;
+#[cfg(feature = "esp")]
 pub mod dht20;
+#[cfg(feature = "esp")]
+pub mod led;
+#[cfg(feature = "esp")]
+pub mod sensor;
 pub mod ssd1683;
+#[cfg(feature = "esp")]
+pub mod watchdog;
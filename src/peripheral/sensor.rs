@@ -0,0 +1,70 @@
+use crate::error::{Result, WmError};
+
+use std::collections::VecDeque;
+
+/// One indoor measurement in °C, % and hPa. Only sensors with a barometer
+/// report the pressure.
+#[derive(Clone, Copy, Debug)]
+pub struct Reading {
+    pub temperature: f32,
+    pub humidity: f32,
+    pub pressure: Option<f32>,
+}
+
+/// A missing or unwired sensor fails `read` with `WmError::SensorMissing`,
+/// which callers can tell apart from a corrupted reading.
+pub trait IndoorSensor {
+    fn read(&mut self) -> Result<Reading>;
+}
+
+/// Turns the I2C errors that mean nothing answered at the sensor address
+/// into `SensorMissing`: ESP_FAIL for a NACK and a timeout for a bus left
+/// floating. Other errors, like a failed CRC, pass through.
+pub fn check_presence(err: WmError) -> WmError {
+    match &err {
+        WmError::EspError(esp)
+            if esp.code() == esp_idf_sys::ESP_FAIL
+                || esp.code() == esp_idf_sys::ESP_ERR_TIMEOUT as i32 =>
+        {
+            WmError::SensorMissing
+        }
+        _ => err,
+    }
+}
+
+/// Reports the mean of the last `window` readings of the wrapped sensor.
+/// Samples are only taken when `read` is called, so the smoothing period is
+/// `window` times the caller's polling interval (5 minutes in `app_main`).
+pub struct Smoothed<'a> {
+    sensor: Box<dyn IndoorSensor + 'a>,
+    window: usize,
+    history: VecDeque<Reading>,
+}
+
+impl<'a> Smoothed<'a> {
+    pub fn new(sensor: Box<dyn IndoorSensor + 'a>, window: usize) -> Self {
+        Smoothed {
+            sensor,
+            window: window.max(1),
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl IndoorSensor for Smoothed<'_> {
+    fn read(&mut self) -> Result<Reading> {
+        let sample = self.sensor.read()?;
+        self.history.push_back(sample);
+        while self.history.len() > self.window {
+            self.history.pop_front();
+        }
+        let count = self.history.len() as f32;
+        let mean = |value: fn(&Reading) -> f32| self.history.iter().map(value).sum::<f32>() / count;
+        Ok(Reading {
+            temperature: mean(|reading| reading.temperature),
+            humidity: mean(|reading| reading.humidity),
+            // Only the latest pressure, it changes too slowly to need smoothing
+            pressure: sample.pressure,
+        })
+    }
+}
@@ -1,8 +1,12 @@
-use crate::display::{Color, Display};
-use crate::error::Result;
+use crate::display::{Color, Display, GrayColor};
+use crate::error::{Result, WmError};
+use crate::peripheral::device::Device;
+#[cfg(feature = "esp")]
+use crate::peripheral::led::Led;
+#[cfg(feature = "esp")]
 use esp_idf_hal::{gpio, spi, units};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const DRIVER_CONTROL: u8 = 0x01;
 const DEEP_SLEEP_MODE: u8 = 0x10;
@@ -18,54 +22,148 @@ const WRITE_RAM: u8 = 0x24;
 const WRITE_ALTRAM: u8 = 0x26;
 const MASTER_ACTIVATE: u8 = 0x20;
 const SOFT_RESET: u8 = 0x12;
+const WRITE_TEMPERATURE: u8 = 0x1A;
 
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+// Largest panel the controller RAM can address
+const MAX_WIDTH: usize = 400;
+const MAX_HEIGHT: usize = 300;
+// Largest single SPI transaction the DMA channel is set up for, without DMA
+// the driver splits them further
+const MAX_TRANSFER_SIZE: usize = 4096;
+
+#[cfg(feature = "esp")]
 pub struct SSD1683<'a> {
     device: spi::SpiSingleDeviceDriver<'a>,
-    dc_pin: gpio::PinDriver<'a, gpio::Gpio13, gpio::Output>,
-    reset_pin: gpio::PinDriver<'a, gpio::Gpio14, gpio::Output>,
-    busy_pin: gpio::PinDriver<'a, gpio::Gpio12, gpio::Input>,
+    dc_pin: gpio::PinDriver<'a, gpio::AnyOutputPin, gpio::Output>,
+    reset_pin: gpio::PinDriver<'a, gpio::AnyOutputPin, gpio::Output>,
+    busy_pin: gpio::PinDriver<'a, gpio::AnyInputPin, gpio::Input>,
+    sleeping: bool,
+    busy_timeout: Duration,
+    led: Option<Led>,
 }
 
+#[cfg(feature = "esp")]
 pub struct SSD1683Gpio {
-    // * BUSY -- GPIO12
-    // * RST  -- GPIO14
-    // * DC   -- GPIO13
-    // * CS   -- GPIO5
-    // * SCK  -- GPIO18
-    // * SDA  -- GPIO23
-    pub gpio5: gpio::Gpio5,
-    pub gpio12: gpio::Gpio12,
-    pub gpio13: gpio::Gpio13,
-    pub gpio14: gpio::Gpio14,
-    pub gpio18: gpio::Gpio18,
-    pub gpio23: gpio::Gpio23,
+    pub busy: gpio::AnyInputPin,
+    pub reset: gpio::AnyOutputPin,
+    pub dc: gpio::AnyOutputPin,
+    pub cs: gpio::AnyOutputPin,
+    pub sclk: gpio::AnyOutputPin,
+    pub sdo: gpio::AnyOutputPin,
 }
 
+#[cfg(feature = "esp")]
 impl SSD1683<'_> {
-    pub fn new(gpio: SSD1683Gpio, spi2: spi::SPI2) -> Result<Self> {
-        let dc_pin = gpio::PinDriver::output(gpio.gpio13)?;
-        let reset_pin = gpio::PinDriver::output(gpio.gpio14)?;
-        let busy_pin = gpio::PinDriver::input(gpio.gpio12)?;
+    /// Drives the panel over SPI2, with DMA when `dma` is set. 20MHz works on
+    /// short wires, lower `baud_rate` if frames come out corrupted.
+    pub fn new(
+        gpio: SSD1683Gpio,
+        spi2: spi::SPI2,
+        baud_rate: units::Hertz,
+        dma: bool,
+    ) -> Result<Self> {
+        let dc_pin = gpio::PinDriver::output(gpio.dc)?;
+        let reset_pin = gpio::PinDriver::output(gpio.reset)?;
+        let busy_pin = gpio::PinDriver::input(gpio.busy)?;
         let dummy: Option<gpio::AnyIOPin> = None;
 
-        let spi_driver =
-            spi::SpiDriver::new(spi2, gpio.gpio18, gpio.gpio23, dummy, spi::Dma::Disabled)?;
+        let dma = if dma {
+            spi::Dma::Auto(MAX_TRANSFER_SIZE)
+        } else {
+            spi::Dma::Disabled
+        };
+        let spi_driver = spi::SpiDriver::new(spi2, gpio.sclk, gpio.sdo, dummy, dma)?;
 
-        let config = spi::SpiConfig::new().baudrate(units::Hertz(20000000));
+        let config = spi::SpiConfig::new().baudrate(baud_rate);
 
-        let device = spi::SpiSingleDeviceDriver::new(spi_driver, Some(gpio.gpio5), &config)?;
+        let device = spi::SpiSingleDeviceDriver::new(spi_driver, Some(gpio.cs), &config)?;
 
         let context = SSD1683 {
             device,
             dc_pin,
             reset_pin,
             busy_pin,
+            sleeping: false,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            led: None,
         };
 
         Ok(context)
     }
 
-    pub fn draw(&mut self, screen: &Display, fast: bool) -> Result<()> {
+    /// Flashes `led` while the panel is being refreshed.
+    pub fn with_led(mut self, led: Option<Led>) -> Self {
+        self.led = led;
+        self
+    }
+
+    fn draw_frame(&mut self, screen: &Display, fast: bool) -> Result<()> {
+        let temperature = if fast { Some(0x6E) } else { None };
+        self.init_panel(screen, temperature)?;
+
+        let data = self.build_ram_data(screen, Color::White);
+        self.send_command(WRITE_RAM)?;
+        self.send_data(&data)?;
+
+        let data = self.build_ram_data(screen, Color::Red);
+        self.send_command(WRITE_ALTRAM)?;
+        self.send_data(&data)?;
+
+        if fast {
+            self.send_command_data(DISPLAY_OPTION, 0xC7)?;
+        } else {
+            self.send_command_data(DISPLAY_OPTION, 0xF7)?;
+        }
+
+        self.send_command(MASTER_ACTIVATE)?;
+        self.wait_for_busy()?;
+        self.sleep()?;
+        Ok(())
+    }
+
+    /// Renders the gray levels stored in `screen` (see `Display::set_gray_pixel`)
+    /// using the 4-level grayscale waveform. Only panel variants that ship the
+    /// grayscale waveform in OTP support this.
+    pub fn draw_grayscale(&mut self, screen: &Display) -> Result<()> {
+        // Loading the magic temperature value selects the 4-gray waveform
+        self.init_panel(screen, Some(0x5A))?;
+
+        let data = self.build_gray_ram_data(screen, |level| {
+            matches!(level, GrayColor::White | GrayColor::LightGray)
+        });
+        self.send_command(WRITE_RAM)?;
+        self.send_data(&data)?;
+
+        let data = self.build_gray_ram_data(screen, |level| {
+            matches!(level, GrayColor::White | GrayColor::DarkGray)
+        });
+        self.send_command(WRITE_ALTRAM)?;
+        self.send_data(&data)?;
+
+        self.send_command_data(DISPLAY_OPTION, 0xCF)?;
+        self.send_command(MASTER_ACTIVATE)?;
+        self.wait_for_busy()?;
+        self.sleep()?;
+        Ok(())
+    }
+
+    /// Rejects buffers the controller can't take: rows are packed into whole
+    /// bytes, so the width must be a multiple of 8.
+    fn check_dimensions(screen: &Display) -> Result<()> {
+        let (width, height) = (screen.get_width(), screen.get_height());
+        if width == 0 || height == 0 || width % 8 != 0 || width > MAX_WIDTH || height > MAX_HEIGHT {
+            println!(
+                "Unsupported panel size {}x{}, the width must be a multiple of 8 and at most {}x{}",
+                width, height, MAX_WIDTH, MAX_HEIGHT
+            );
+            return Err(WmError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    fn init_panel(&mut self, screen: &Display, temperature: Option<u8>) -> Result<()> {
+        Self::check_dimensions(screen)?;
         self.reset()?;
 
         self.send_command(DRIVER_CONTROL)?;
@@ -75,12 +173,12 @@ impl SSD1683<'_> {
             0,
         ])?;
 
-        if fast {
-            self.send_command(0x1A)?;
-            self.send_data(&[0x6E, 0x00])?;
+        if let Some(temperature) = temperature {
+            self.send_command(WRITE_TEMPERATURE)?;
+            self.send_data(&[temperature, 0x00])?;
             self.send_command_data(DISPLAY_OPTION, 0x91)?;
             self.send_command(MASTER_ACTIVATE)?;
-            self.wait_for_busy();
+            self.wait_for_busy()?;
         }
 
         self.send_command_data(DATA_MODE, 0x03)?;
@@ -104,41 +202,50 @@ impl SSD1683<'_> {
         self.send_command_data(SET_RAMXCOUNT, 0x00)?;
         self.send_command(SET_RAMYCOUNT)?;
         self.send_data(&[0x00, 0x00])?;
+        Ok(())
+    }
 
-        let data = self.build_ram_data(screen, Color::White);
-        self.send_command(WRITE_RAM)?;
-        self.send_data(&data)?;
-
-        let data = self.build_ram_data(screen, Color::Red);
-        self.send_command(WRITE_ALTRAM)?;
-        self.send_data(&data)?;
+    /// Sets how long to wait for the BUSY line before giving up on the panel.
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.busy_timeout = timeout;
+    }
 
-        if fast {
-            self.send_command_data(DISPLAY_OPTION, 0xC7)?;
-        } else {
-            self.send_command_data(DISPLAY_OPTION, 0xF7)?;
+    fn wait_for_busy(&self) -> Result<()> {
+        let start = Instant::now();
+        while self.busy_pin.is_high() {
+            if start.elapsed() >= self.busy_timeout {
+                return Err(WmError::InternalError);
+            }
+            sleep(Duration::from_millis(10));
         }
-
-        self.send_command(MASTER_ACTIVATE)?;
-        self.wait_for_busy();
-        self.send_command_data(DEEP_SLEEP_MODE, 0x03)?;
         Ok(())
     }
 
-    fn wait_for_busy(&self) {
-        while self.busy_pin.is_high() {
-            sleep(Duration::from_millis(10));
+    /// Puts the controller into deep sleep mode 1, which keeps the RAM contents
+    /// but powers down the analog circuits. Only a hardware reset wakes it up.
+    pub fn sleep(&mut self) -> Result<()> {
+        if !self.sleeping {
+            self.send_command_data(DEEP_SLEEP_MODE, 0x01)?;
+            self.sleeping = true;
         }
+        Ok(())
     }
 
-    fn reset(&mut self) -> Result<()> {
+    fn wake(&mut self) -> Result<()> {
         self.reset_pin.set_low()?;
         sleep(Duration::from_millis(10));
         self.reset_pin.set_high()?;
         sleep(Duration::from_millis(10));
+        self.wait_for_busy()?;
+        self.sleeping = false;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.wake()?;
         self.send_command(SOFT_RESET)?;
         sleep(Duration::from_secs(1));
-        self.wait_for_busy();
+        self.wait_for_busy()?;
         Ok(())
     }
 
@@ -149,9 +256,14 @@ impl SSD1683<'_> {
         Ok(())
     }
 
+    /// Writes `data` in `transfers`, a full plane is 15000 bytes. DC stays
+    /// high throughout and the controller keeps advancing its RAM address
+    /// across the chunks.
     fn send_data(&mut self, data: &[u8]) -> Result<()> {
         self.dc_pin.set_high()?;
-        self.device.write(data)?;
+        for chunk in transfers(data) {
+            self.device.write(chunk)?;
+        }
         Ok(())
     }
 
@@ -162,12 +274,30 @@ impl SSD1683<'_> {
     }
 
     fn build_ram_data(&self, screen: &Display, color: Color) -> Vec<u8> {
+        // `check_dimensions` made sure rows end on a byte boundary
+        let mut data = Vec::<u8>::new();
+        data.resize(screen.get_width() / 8 * screen.get_height(), 0);
+        for x in 0..screen.get_width() {
+            for y in 0..screen.get_height() {
+                let pos = x + y * screen.get_width();
+                if screen.get_panel_pixel(x, y).unwrap() == color {
+                    data[pos / 8] |= 1u8 << (7 - (pos % 8));
+                }
+            }
+        }
+        data
+    }
+
+    fn build_gray_ram_data<F>(&self, screen: &Display, plane: F) -> Vec<u8>
+    where
+        F: Fn(GrayColor) -> bool,
+    {
         let mut data = Vec::<u8>::new();
-        data.resize(screen.get_width() * screen.get_height() / 8, 0);
+        data.resize(screen.get_width() / 8 * screen.get_height(), 0);
         for x in 0..screen.get_width() {
             for y in 0..screen.get_height() {
                 let pos = x + y * screen.get_width();
-                if screen.get_pixel(x, y).unwrap() == color {
+                if plane(screen.get_panel_gray_pixel(x, y).unwrap()) {
                     data[pos / 8] |= 1u8 << (7 - (pos % 8));
                 }
             }
@@ -175,3 +305,43 @@ impl SSD1683<'_> {
         data
     }
 }
+
+#[cfg(feature = "esp")]
+impl Device for SSD1683<'_> {
+    fn draw(&mut self, screen: &Display, fast: bool) -> Result<()> {
+        match self.led.clone() {
+            Some(led) => led.flash_while(|| self.draw_frame(screen, fast)),
+            None => self.draw_frame(screen, fast),
+        }
+    }
+
+    fn supports_fast(&self) -> bool {
+        true
+    }
+}
+
+/// Splits `data` into SPI transactions of at most MAX_TRANSFER_SIZE bytes.
+fn transfers(data: &[u8]) -> std::slice::Chunks<'_, u8> {
+    data.chunks(MAX_TRANSFER_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer_sizes(len: usize) -> Vec<usize> {
+        transfers(&vec![0u8; len])
+            .map(|chunk| chunk.len())
+            .collect()
+    }
+
+    #[test]
+    fn transfers_at_the_limit() {
+        assert_eq!(transfer_sizes(4095), vec![4095]);
+        assert_eq!(transfer_sizes(4096), vec![4096]);
+        assert_eq!(transfer_sizes(4097), vec![4096, 1]);
+        // A full plane of the 400x300 panel
+        assert_eq!(transfer_sizes(15000), vec![4096, 4096, 4096, 2712]);
+        assert!(transfer_sizes(0).is_empty());
+    }
+}
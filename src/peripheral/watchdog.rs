@@ -0,0 +1,30 @@
+use crate::error::Result;
+
+use esp_idf_sys::{
+    esp, esp_task_wdt_add, esp_task_wdt_delete, esp_task_wdt_init, esp_task_wdt_reset,
+};
+use std::ptr;
+
+/// Subscribes the calling task to the ESP task watchdog, which reboots the
+/// device unless `feed` is called from that task within the timeout.
+pub struct Watchdog;
+
+impl Watchdog {
+    pub fn new(timeout_secs: u32) -> Result<Self> {
+        // Reconfigures the watchdog if ESP-IDF already started it at boot
+        esp!(unsafe { esp_task_wdt_init(timeout_secs, true) })?;
+        esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) })?;
+        Ok(Watchdog)
+    }
+
+    /// Must be called from the task that created the watchdog.
+    pub fn feed(&self) {
+        unsafe { esp_task_wdt_reset() };
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        unsafe { esp_task_wdt_delete(ptr::null_mut()) };
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::Config;
+use crate::error::{Result, WmError};
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use serde::{Deserialize, Serialize};
+use time::UtcOffset;
+
+const NVS_NAMESPACE: &str = "settings";
+const NVS_KEY: &str = "weather";
+const NVS_MAX_SIZE: usize = 512;
+
+/// Settings changed at runtime through the web interface, overriding the
+/// values compiled in from `cfg.toml`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub location: String,
+    pub key: String,
+    pub utc_offset_hours: i8,
+    pub refresh_start_hour: u8,
+    pub refresh_end_hour: u8,
+    pub refresh_interval_minutes: u32,
+}
+
+impl Settings {
+    pub fn from_config(conf: &Config) -> Self {
+        Settings {
+            location: conf.location.into(),
+            key: conf.qweather_key.into(),
+            utc_offset_hours: conf.utc_offset_hours,
+            refresh_start_hour: conf.refresh_start_hour,
+            refresh_end_hour: conf.refresh_end_hour,
+            refresh_interval_minutes: conf.refresh_interval_minutes,
+        }
+    }
+
+    /// Returns the compiled defaults with any field stored in NVS applied on
+    /// top, so blobs written by older firmware still load.
+    pub fn load(nvs: &EspDefaultNvsPartition, conf: &Config) -> Self {
+        let defaults = Settings::from_config(conf);
+        match Self::load_stored(nvs, &defaults) {
+            Ok(settings) => settings,
+            Err(err) => {
+                println!("No saved settings loaded: {}", err);
+                defaults
+            }
+        }
+    }
+
+    fn load_stored(nvs: &EspDefaultNvsPartition, defaults: &Settings) -> Result<Self> {
+        let storage = EspDefaultNvs::new(nvs.clone(), NVS_NAMESPACE, true)?;
+        let mut buf = Vec::new();
+        buf.resize(NVS_MAX_SIZE, 0);
+        let blob = storage
+            .get_raw(NVS_KEY, &mut buf)?
+            .ok_or(WmError::InvalidArgument)?;
+        let stored: serde_json::Value = serde_json::from_slice(blob)?;
+        let mut merged = serde_json::to_value(defaults)?;
+        if let (Some(merged), Some(stored)) = (merged.as_object_mut(), stored.as_object()) {
+            for (name, value) in stored {
+                merged.insert(name.clone(), value.clone());
+            }
+        }
+        Ok(serde_json::from_value(merged)?)
+    }
+
+    pub fn save(&self, nvs: &EspDefaultNvsPartition) -> Result<()> {
+        let mut storage = EspDefaultNvs::new(nvs.clone(), NVS_NAMESPACE, true)?;
+        let blob = serde_json::to_vec(self)?;
+        if blob.len() > NVS_MAX_SIZE {
+            return Err(WmError::InvalidArgument);
+        }
+        storage.set_raw(NVS_KEY, &blob)?;
+        Ok(())
+    }
+
+    /// Drops everything stored in NVS so the compiled defaults apply again.
+    pub fn clear(nvs: &EspDefaultNvsPartition) -> Result<()> {
+        let mut storage = EspDefaultNvs::new(nvs.clone(), NVS_NAMESPACE, true)?;
+        storage.remove(NVS_KEY)?;
+        Ok(())
+    }
+
+    /// Rejects values the refresh schedule or the clock can't work with.
+    pub fn validate(&self) -> Result<()> {
+        if self.location.trim().is_empty()
+            || self.refresh_start_hour > 23
+            || self.refresh_end_hour > 23
+            || self.refresh_interval_minutes == 0
+            || UtcOffset::from_hms(self.utc_offset_hours, 0, 0).is_err()
+        {
+            return Err(WmError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    pub fn utc_offset(&self) -> UtcOffset {
+        UtcOffset::from_hms(self.utc_offset_hours, 0, 0).unwrap_or(UtcOffset::UTC)
+    }
+}